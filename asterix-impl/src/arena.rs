@@ -0,0 +1,176 @@
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote, TokenStreamExt};
+
+use crate::context::{lowercase, Context};
+
+/// The `AstArena` of arena mode, holding the nodes of every boxed type, and
+/// the `{Name}Id` handles into it.
+pub struct Arena<'c> {
+    context: &'c Context,
+}
+
+impl<'c> Arena<'c> {
+    pub fn new(context: &'c Context) -> Self {
+        Self { context }
+    }
+
+    pub fn create_arena(&self) -> TokenStream {
+        let mut tokens = TokenStream::default();
+
+        if !self.context.arena {
+            return tokens;
+        }
+
+        let names = &self.context.arena_types;
+        let ids: Vec<_> = names.iter().map(|n| format_ident!("{}Id", n)).collect();
+        let fields: Vec<_> = names
+            .iter()
+            .map(|n| format_ident!("{}_nodes", n.to_string().to_lowercase()))
+            .collect();
+        let allocs = names
+            .iter()
+            .map(|n| format_ident!("alloc_{}", n.to_string().to_lowercase()));
+        let args = names.iter().map(lowercase);
+        let args_repeat = args.clone();
+        let types: Vec<TokenStream> = names.iter().map(|n| self.context.ty(n)).collect();
+        let serde = self.context.serde_derive();
+
+        let generics = self.context.arena_generics();
+        let (params, arena_args, where_clause) = generics.split_for_impl();
+
+        tokens.append_all(quote! {
+            /// Owns every node that is referred to by id.
+            #[derive(Debug, Clone)]
+            #serde
+            pub struct AstArena #params #where_clause {
+                #(#fields: Vec<#types>),*
+            }
+
+            impl #params Default for AstArena #arena_args #where_clause {
+                fn default() -> Self {
+                    Self {
+                        #(#fields: Vec::new()),*
+                    }
+                }
+            }
+
+            impl #params AstArena #arena_args #where_clause {
+                pub fn new() -> Self {
+                    Self::default()
+                }
+
+                #(
+                    pub fn #allocs(&mut self, #args: #types) -> #ids {
+                        self.#fields.push(#args_repeat);
+                        #ids(self.#fields.len() - 1)
+                    }
+                )*
+
+                /// The node `id` refers to. An id is only meaningful in the
+                /// arena that allocated it: one allocated in another arena
+                /// refers to whichever node has its index here.
+                ///
+                /// # Panics
+                ///
+                /// If there is no node with the index of `id`, which can only
+                /// happen for an id allocated in another arena.
+                pub fn get<I: ArenaId #arena_args>(&self, id: I) -> &I::Node {
+                    &I::nodes(self)[id.index()]
+                }
+
+                /// The node `id` refers to, see `get`.
+                ///
+                /// # Panics
+                ///
+                /// If there is no node with the index of `id`, which can only
+                /// happen for an id allocated in another arena.
+                pub fn get_mut<I: ArenaId #arena_args>(&mut self, id: I) -> &mut I::Node {
+                    &mut I::nodes_mut(self)[id.index()]
+                }
+            }
+
+            /// A handle to a node in an `AstArena`.
+            pub trait ArenaId #params: Copy #where_clause {
+                type Node;
+
+                fn index(self) -> usize;
+
+                fn nodes(arena: &AstArena #arena_args) -> &[Self::Node];
+
+                fn nodes_mut(arena: &mut AstArena #arena_args) -> &mut [Self::Node];
+            }
+
+            #(
+                #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+                #serde
+                pub struct #ids(usize);
+
+                impl #params ArenaId #arena_args for #ids #where_clause {
+                    type Node = #types;
+
+                    fn index(self) -> usize {
+                        self.0
+                    }
+
+                    fn nodes(arena: &AstArena #arena_args) -> &[#types] {
+                        &arena.#fields
+                    }
+
+                    fn nodes_mut(arena: &mut AstArena #arena_args) -> &mut [#types] {
+                        &mut arena.#fields
+                    }
+                }
+            )*
+        });
+
+        tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::NewType;
+    use quote::ToTokens;
+    use syn::parse_quote;
+
+    #[test]
+    fn boxed_ids() {
+        let context: Context = parse_quote! {
+            #![arena]
+            Lit |isize|,
+            Expr: enum Expr {
+                Neg(Box<Expr>),
+                Call: struct Call {
+                    callee: Box<Expr>,
+                    args: Vec<Option<Box<Expr>>>,
+                    name: Box<String>,
+                },
+                |Lit|,
+            }
+        };
+
+        let names: Vec<String> = context.arena_types.iter().map(|n| n.to_string()).collect();
+        assert_eq!(names, vec!["Expr"]);
+
+        let call = context
+            .new_types
+            .iter()
+            .find_map(|nt| match nt {
+                NewType::Struct(s) => Some(s),
+                _ => None,
+            })
+            .unwrap();
+        let types: Vec<String> = call
+            .fields
+            .iter()
+            .map(|f| f.ty.to_token_stream().to_string())
+            .collect();
+        assert_eq!(
+            types,
+            vec!["ExprId", "Vec < Option < ExprId > >", "Box < String >"]
+        );
+
+        println!("{}", Arena::new(&context).create_arena());
+    }
+}
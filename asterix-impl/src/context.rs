@@ -1,37 +1,84 @@
+use std::collections::{HashMap, HashSet};
+
 use proc_macro2::{Span, TokenStream};
-use quote::{quote, ToTokens, TokenStreamExt};
+use quote::{format_ident, quote, ToTokens, TokenStreamExt};
 use syn::{
     braced, parenthesized,
-    parse::{Parse, ParseStream},
+    parse::{Parse, ParseStream, Parser},
     parse_quote,
     punctuated::Punctuated,
-    token::Paren,
-    Ident, Result, Token, Type,
+    token::{Brace, Paren},
+    Attribute, GenericArgument, GenericParam, Generics, Ident, Lifetime, LifetimeDef, NestedMeta,
+    Path, PathArguments, Result, Token, Type, Visibility, WhereClause, WherePredicate,
 };
 
-#[derive(Default, Debug)]
+use crate::shape::Shape;
+
+#[derive(Debug)]
 pub struct Context {
     pub new_types: Vec<NewType>,
     pub variants: Punctuated<Variant, Token![,]>,
+    /// Set by `#![stack_safe_drop]`: recursive types tear themselves down
+    /// without recursing. Each one held other than inline needs a value
+    /// without children or leaves, like a variant without fields. The structs
+    /// and enums among them implement `Drop`, so their fields can no longer be
+    /// moved out of them: `let BinOp { lhs, .. } = b;` doesn't compile, and a
+    /// `Fold` or `std::mem::replace` through the `_mut` accessors has to be used
+    /// instead.
+    pub stack_safe_drop: bool,
+    /// Set by `#![arena]`: every `Box<T>` of a new type `T` is replaced by a
+    /// `TId` handle into the `AstArena`, and these are the types boxed.
+    pub arena: bool,
+    pub arena_types: Vec<Ident>,
+    /// Set by `#![span]` or `#![span(Type)]`: every struct and wrapper carries
+    /// a span, of the given type or of a generated `Span`. A given type has to
+    /// be `Clone`, `Debug` and `Default`, the span of nodes built without one.
+    pub spans: bool,
+    pub span_type: Option<Type>,
+    /// Set by `#![serde]` or `#![serde(..)]`: every generated type derives
+    /// `Serialize` and `Deserialize`, with these container attributes. A `tag`
+    /// without a `content` needs every variant to hold named fields or a
+    /// struct of them.
+    pub serde: Option<Punctuated<NestedMeta, Token![,]>>,
+    /// Set by `#![annotated]` or `#![annotated(Name)]`: every new type
+    /// carries an annotation whose type is the parameter `Name`, or `Ann`.
+    /// Not available with `#![arena]`.
+    pub annotation: Option<Ident>,
+    /// Set by `#![module = .., root = .., visitor = ..]`: the names of the
+    /// generated module, of the root enum over the top-level variants, or
+    /// `None` for `root = _`, and of the visitor traits.
+    pub module: Ident,
+    pub root: Option<Ident>,
+    pub visitor: Ident,
+    /// The generic parameters of every new type together, which the items
+    /// spanning all of them, like the visitors and `NodeRef`, are generic over.
+    pub generics: Generics,
 }
 
 impl Context {
     pub fn create_ast(self, visitor: Option<TokenStream>) -> proc_macro2::TokenStream {
-        let ast_name = Ident::new("Ast", Span::call_site());
-        let new_types = self.new_types;
-        let ast = EnumType {
-            name: ast_name,
-            variants: self.variants,
-        };
+        let module = self.module.clone();
+        let items = self.create_items(visitor);
 
         quote! {
-            pub mod ast {
-                #(#new_types)*
-                #ast
-                #visitor
+            pub mod #module {
+                #items
             }
         }
     }
+
+    /// The new types, the root and `visitor`, without the module around
+    /// them.
+    pub fn create_items(self, visitor: Option<TokenStream>) -> proc_macro2::TokenStream {
+        let ast = self.root_type();
+        let new_types = self.new_types;
+
+        quote! {
+            #(#new_types)*
+            #ast
+            #visitor
+        }
+    }
 }
 
 fn flatten(new_type: &mut NewType) -> Vec<NewType> {
@@ -42,11 +89,7 @@ fn flatten(new_type: &mut NewType) -> Vec<NewType> {
     match new_type {
         NewType::Enum(e) => {
             for variant in &mut e.variants {
-                if let Some(ref mut nt) = &mut variant.new_type {
-                    types.append(&mut flatten(nt));
-                }
-
-                variant.new_type = None;
+                types.append(&mut variant.take_new_types());
             }
         }
         NewType::Struct(s) => {
@@ -66,25 +109,613 @@ fn flatten(new_type: &mut NewType) -> Vec<NewType> {
 
 impl Parse for Context {
     fn parse(input: ParseStream) -> Result<Self> {
+        Self::parse_in_scope(input, Some(&[]))
+    }
+}
+
+impl Context {
+    /// Parses a grammar that can also name the types `in_scope` without a
+    /// path, or any type with `None`.
+    pub fn parse_in_scope(input: ParseStream, in_scope: Option<&[Ident]>) -> Result<Self> {
+        let mut stack_safe_drop = false;
+        let mut arena = false;
+        let mut spans = false;
+        let mut span_type = None;
+        let mut serde = None;
+        let mut annotation = None;
+        let mut annotated = None;
+        let mut module = Ident::new("ast", Span::call_site());
+        let mut root = Some(Ident::new("Ast", Span::call_site()));
+        let mut visitor = Ident::new("Visitor", Span::call_site());
+
+        for attr in input.call(Attribute::parse_inner)? {
+            if attr.path.is_ident("module")
+                || attr.path.is_ident("root")
+                || attr.path.is_ident("visitor")
+            {
+                let path = &attr.path;
+                let tokens = &attr.tokens;
+                let settings = Punctuated::<Setting, Token![,]>::parse_terminated
+                    .parse2(quote! { #path #tokens })?;
+
+                for setting in settings {
+                    match (setting.key.to_string().as_str(), setting.value) {
+                        ("module", Some(value)) => module = value,
+                        ("root", value) => root = value,
+                        ("visitor", Some(value)) => visitor = value,
+                        (_, None) => {
+                            return Err(syn::Error::new_spanned(
+                                setting.key,
+                                "only the root can be left out",
+                            ))
+                        }
+                        _ => {
+                            return Err(syn::Error::new_spanned(
+                                setting.key,
+                                "unknown ast! setting, expected `module`, `root` or `visitor`",
+                            ))
+                        }
+                    }
+                }
+            } else if attr.path.is_ident("stack_safe_drop") && attr.tokens.is_empty() {
+                stack_safe_drop = true;
+            } else if attr.path.is_ident("arena") && attr.tokens.is_empty() {
+                arena = true;
+            } else if attr.path.is_ident("span") {
+                spans = true;
+                if !attr.tokens.is_empty() {
+                    span_type = Some(attr.parse_args::<Type>()?);
+                }
+            } else if attr.path.is_ident("annotated") {
+                annotated = Some(attr.clone());
+                annotation = Some(if attr.tokens.is_empty() {
+                    Ident::new("Ann", Span::call_site())
+                } else {
+                    attr.parse_args::<Ident>()?
+                });
+            } else if attr.path.is_ident("serde") {
+                if !cfg!(feature = "serde") {
+                    return Err(syn::Error::new_spanned(
+                        attr,
+                        "the `serde` feature of asterix is not enabled",
+                    ));
+                }
+
+                let args: Punctuated<NestedMeta, Token![,]> = if attr.tokens.is_empty() {
+                    Punctuated::new()
+                } else {
+                    attr.parse_args_with(Punctuated::parse_terminated)?
+                };
+
+                serde = Some(args);
+            } else {
+                return Err(syn::Error::new_spanned(attr, "unknown ast! option"));
+            }
+        }
+
+        // The ids of an arena would still point at nodes with annotations of
+        // the old type after `MapAnnotations`
+        if let (true, Some(annotated)) = (arena, annotated) {
+            return Err(syn::Error::new_spanned(
+                annotated,
+                "`#![annotated]` can't be combined with `#![arena]`",
+            ));
+        }
+
         let mut variants = input.parse_terminated(Variant::parse)?;
         let mut new_types = Vec::new();
 
+        // Without a root, a top-level variant only declares its new type
+        if root.is_none() {
+            if let Some(variant) = variants.iter().find(|v| v.new_type.is_none()) {
+                return Err(syn::Error::new_spanned(
+                    &variant.name,
+                    "without a root, every top-level entry must declare a new type",
+                ));
+            }
+        }
+
         for variant in &mut variants {
             // println!("VARIANT: {:?}", variant);
+            new_types.extend(variant.take_new_types());
+        }
 
-            if let Some(nt) = &mut variant.new_type {
-                // println!("NEW TYPE {:?}\n", nt);
-                let flattened = flatten(nt);
-                new_types.extend(flattened.into_iter());
+        let mut context = Context {
+            new_types: Vec::new(),
+            variants,
+            stack_safe_drop,
+            arena,
+            arena_types: Vec::new(),
+            spans,
+            span_type,
+            serde,
+            annotation,
+            module,
+            root,
+            visitor,
+            generics: Generics::default(),
+        };
+
+        for new_type in &mut new_types {
+            match new_type {
+                NewType::Struct(s) => {
+                    s.spanned = spans;
+                    s.attrs.extend(context.type_attrs(false));
+                }
+                NewType::WrapperStruct(w) => {
+                    w.spanned = spans;
+                    w.attrs.extend(context.type_attrs(false));
+                }
+                NewType::Enum(e) => e.attrs.extend(context.type_attrs(true)),
             }
+        }
+        context.new_types = new_types;
+        crate::validate::validate(&context, in_scope)?;
+        context.annotate();
+        context.generics = union(context.new_types.iter().map(|nt| nt.generics()));
+        context.fill_generics();
+
+        if arena {
+            context.box_to_ids();
+        }
+
+        Ok(context)
+    }
+}
+
+/// One `name = value` of the configuration header, where the value `_`
+/// leaves the item out.
+struct Setting {
+    key: Ident,
+    value: Option<Ident>,
+}
 
-            variant.new_type = None;
+impl Parse for Setting {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let key = input.parse::<Ident>()?;
+        input.parse::<Token![=]>()?;
+
+        let value = if input.peek(Token![_]) {
+            input.parse::<Token![_]>()?;
+            None
+        } else {
+            Some(input.parse::<Ident>()?)
+        };
+
+        Ok(Setting { key, value })
+    }
+}
+
+impl Context {
+    /// The root enum over the top-level variants, unless it is left out. It
+    /// takes the parameters that its variants use.
+    pub fn root_type(&self) -> Option<NewType> {
+        let name = self.root.clone()?;
+
+        let mut used = Vec::new();
+        for ty in self.variants.iter().flat_map(|v| v.types()) {
+            idents(ty.to_token_stream(), &mut used);
         }
 
-        Ok(Context {
-            new_types,
-            variants,
+        let mut generics = self.generics.clone();
+        let (kept, dropped): (Vec<GenericParam>, Vec<GenericParam>) = generics
+            .params
+            .into_iter()
+            .partition(|param| used.contains(&param_ident(param).to_string()));
+        generics.params = kept.into_iter().collect();
+
+        // As are the bounds on the parameters it leaves out
+        if let Some(where_clause) = &mut generics.where_clause {
+            where_clause.predicates = std::mem::take(&mut where_clause.predicates)
+                .into_iter()
+                .filter(|predicate| {
+                    let mut mentioned = Vec::new();
+                    idents(predicate.to_token_stream(), &mut mentioned);
+
+                    !dropped
+                        .iter()
+                        .any(|param| mentioned.contains(&param_ident(param).to_string()))
+                })
+                .collect();
+        }
+
+        Some(NewType::Enum(EnumType {
+            vis: parse_quote! { pub },
+            name,
+            generics,
+            variants: self.variants.clone(),
+            annotation: None,
+            attrs: self.type_attrs(true),
+        }))
+    }
+
+    /// `Name<'a, T>`: the root or new type `name` applied to its own
+    /// parameters.
+    pub fn ty(&self, name: &Ident) -> TokenStream {
+        let root = self.root_type().filter(|root| root.name() == name);
+        let generics = match root
+            .as_ref()
+            .or_else(|| self.new_types.iter().find(|nt| nt.name() == name))
+        {
+            Some(new_type) => new_type.generics(),
+            None => return quote! { #name },
+        };
+        let (_, ty_generics, _) = generics.split_for_impl();
+
+        quote! { #name #ty_generics }
+    }
+
+    /// The parameters of the types kept in the `AstArena`.
+    pub fn arena_generics(&self) -> Generics {
+        union(
+            self.new_types
+                .iter()
+                .filter(|nt| self.arena_types.contains(nt.name()))
+                .map(|nt| nt.generics()),
+        )
+    }
+
+    /// The name of each arena handle type, with the type it refers to. Empty
+    /// outside arena mode.
+    pub fn arena_ids(&self) -> HashMap<String, Ident> {
+        self.arena_types
+            .iter()
+            .map(|node| (format!("{}Id", node), node.clone()))
+            .collect()
+    }
+
+    /// `AstArena<..>`, applied to the parameters of the types it keeps.
+    pub fn arena_type(&self) -> TokenStream {
+        let generics = self.arena_generics();
+        let (_, args, _) = generics.split_for_impl();
+
+        quote! { AstArena #args }
+    }
+
+    /// `generics`, with a `Clone` bound on every generic type kept in the
+    /// `AstArena`, for the items that take a copy of a node out of it.
+    pub fn cloning_arena(&self, generics: &Generics) -> Generics {
+        let mut generics = generics.clone();
+        let generic_types = self
+            .new_types
+            .iter()
+            .filter(|nt| self.arena_types.contains(nt.name()) && !nt.generics().params.is_empty());
+
+        for new_type in generic_types {
+            let ty = self.ty(new_type.name());
+            generics
+                .make_where_clause()
+                .predicates
+                .push(parse_quote! { #ty: Clone });
+        }
+
+        generics
+    }
+
+    /// Gives every new type its annotation and the annotation parameter. An
+    /// enum's variants that hold a node of their own have that node's, and
+    /// every other variant is given one after its fields.
+    fn annotate(&mut self) {
+        let annotation = match &self.annotation {
+            Some(annotation) => annotation.clone(),
+            None => return,
+        };
+        let ty: Type = parse_quote! { #annotation };
+        let new_idents: HashSet<String> = self
+            .new_types
+            .iter()
+            .map(|nt| nt.name().to_string())
+            .collect();
+
+        for new_type in &mut self.new_types {
+            let generics = match new_type {
+                NewType::Enum(e) => {
+                    for variant in &mut e.variants {
+                        annotate_variant(variant, &ty, &new_idents);
+                    }
+
+                    e.annotation = Some(annotation.clone());
+                    &mut e.generics
+                }
+                NewType::Struct(s) => {
+                    s.annotation = Some(annotation.clone());
+                    &mut s.generics
+                }
+                NewType::WrapperStruct(w) => {
+                    w.annotation = Some(annotation.clone());
+                    &mut w.generics
+                }
+            };
+            generics.params.push(parse_quote! { #annotation });
+        }
+    }
+
+    /// Gives every bare mention of a generic new type its own parameters, so
+    /// that `|Expr|` and `Box<Expr>` stand for `Expr<A>`.
+    fn fill_generics(&mut self) {
+        let generic: Vec<(String, TokenStream)> = self
+            .new_types
+            .iter()
+            .filter(|nt| !nt.generics().params.is_empty())
+            .map(|nt| (nt.name().to_string(), self.ty(nt.name())))
+            .collect();
+
+        if generic.is_empty() {
+            return;
+        }
+
+        for ty in self.types_mut() {
+            fill_generics(ty, &generic);
+        }
+    }
+
+    /// Every variant and field type, for rewriting.
+    fn types_mut(&mut self) -> Vec<&mut Type> {
+        let mut types: Vec<&mut Type> = self
+            .variants
+            .iter_mut()
+            .flat_map(|v| v.types_mut())
+            .collect();
+        for new_type in &mut self.new_types {
+            match new_type {
+                NewType::Enum(e) => types.extend(e.variants.iter_mut().flat_map(|v| v.types_mut())),
+                NewType::Struct(s) => types.extend(s.fields.iter_mut().map(|f| &mut f.ty)),
+                NewType::WrapperStruct(w) => types.push(&mut w.ty),
+            }
+        }
+
+        types
+    }
+
+    /// The root, if there is one, and every new type, in the order they are
+    /// declared.
+    pub fn node_names(&self) -> Vec<Ident> {
+        self.root
+            .iter()
+            .chain(self.new_types.iter().map(|nt| nt.name()))
+            .cloned()
+            .collect()
+    }
+
+    /// The serde derives for the generated types that are not nodes.
+    pub fn serde_derive(&self) -> TokenStream {
+        if self.serde.is_none() {
+            return TokenStream::new();
+        }
+
+        quote! {
+            #[derive(::asterix::serde::Serialize, ::asterix::serde::Deserialize)]
+            #[serde(crate = "::asterix::serde")]
+        }
+    }
+
+    /// The attributes of every node type besides its derives. Enums take
+    /// every serde container attribute, and structs only those naming their
+    /// fields, since tagging a struct inside a tagged enum repeats the tag.
+    fn type_attrs(&self, is_enum: bool) -> Vec<Attribute> {
+        let serde = match &self.serde {
+            Some(serde) => serde,
+            None => return Vec::new(),
+        };
+
+        let mut attrs: Vec<Attribute> = vec![
+            parse_quote! { #[derive(::asterix::serde::Serialize, ::asterix::serde::Deserialize)] },
+            parse_quote! { #[serde(crate = "::asterix::serde")] },
+        ];
+
+        let args: Vec<&NestedMeta> = serde
+            .iter()
+            .filter(|arg| {
+                is_enum
+                    || match arg {
+                        NestedMeta::Meta(meta) => {
+                            let path = meta.path();
+                            path.is_ident("rename_all") || path.is_ident("deny_unknown_fields")
+                        }
+                        NestedMeta::Lit(_) => false,
+                    }
+            })
+            .collect();
+        if !args.is_empty() {
+            attrs.push(parse_quote! { #[serde(#(#args),*)] });
+        }
+
+        attrs
+    }
+
+    /// Replaces every `Box<T>` of a new type `T` by `TId`, recording each `T`.
+    fn box_to_ids(&mut self) {
+        let new_idents: Vec<String> = self
+            .new_types
+            .iter()
+            .map(|n| n.name().to_string())
+            .collect();
+        let mut boxed = Vec::new();
+
+        for ty in self.types_mut() {
+            box_to_id(ty, &new_idents, &mut boxed);
+        }
+
+        self.arena_types = self
+            .new_types
+            .iter()
+            .map(|nt| nt.name().clone())
+            .filter(|name| boxed.contains(&name.to_string()))
+            .collect();
+    }
+}
+
+/// `generics` behind the new lifetime `lifetime`, which every other
+/// parameter outlives.
+pub fn outliving(generics: &Generics, lifetime: &str) -> Generics {
+    let lifetime = Lifetime::new(lifetime, Span::call_site());
+    let mut outliving = generics.clone();
+
+    let predicates: Vec<WherePredicate> = generics
+        .params
+        .iter()
+        .filter_map(|param| match param {
+            GenericParam::Lifetime(l) => {
+                let l = &l.lifetime;
+                Some(parse_quote! { #l: #lifetime })
+            }
+            GenericParam::Type(t) => {
+                let t = &t.ident;
+                Some(parse_quote! { #t: #lifetime })
+            }
+            GenericParam::Const(_) => None,
         })
+        .collect();
+    if !predicates.is_empty() {
+        outliving.make_where_clause().predicates.extend(predicates);
+    }
+    outliving
+        .params
+        .insert(0, GenericParam::Lifetime(LifetimeDef::new(lifetime)));
+
+    outliving
+}
+
+/// The parameters of all of `generics`, lifetimes first, and their where
+/// clauses, without repeating a parameter or predicate.
+fn union<'g>(generics: impl Iterator<Item = &'g Generics>) -> Generics {
+    let mut union = Generics::default();
+    let mut params: Vec<GenericParam> = Vec::new();
+    let mut predicates: Vec<WherePredicate> = Vec::new();
+
+    for g in generics {
+        for param in &g.params {
+            if !params.iter().any(|p| param_ident(p) == param_ident(param)) {
+                params.push(param.clone());
+            }
+        }
+
+        for predicate in g.where_clause.iter().flat_map(|w| &w.predicates) {
+            let string = predicate.to_token_stream().to_string();
+            if !predicates
+                .iter()
+                .any(|p| p.to_token_stream().to_string() == string)
+            {
+                predicates.push(predicate.clone());
+            }
+        }
+    }
+
+    params.sort_by_key(|param| !matches!(param, GenericParam::Lifetime(_)));
+    union.params = params.into_iter().collect();
+    if !predicates.is_empty() {
+        union.make_where_clause().predicates = predicates.into_iter().collect();
+    }
+
+    union
+}
+
+/// `ident` in lowercase, for the constructors and arguments named after
+/// types and variants. A keyword is made raw, or suffixed with `_` where it
+/// can't be.
+pub fn lowercase(ident: &Ident) -> Ident {
+    let lower = ident.to_string().to_lowercase();
+
+    if syn::parse_str::<Ident>(&lower).is_ok() {
+        Ident::new(&lower, ident.span())
+    } else if ["self", "super", "crate"].contains(&lower.as_str()) {
+        format_ident!("{}_", lower, span = ident.span())
+    } else {
+        let mut raw: Ident = syn::parse_str(&format!("r#{}", lower)).unwrap();
+        raw.set_span(ident.span());
+        raw
+    }
+}
+
+pub fn param_ident(param: &GenericParam) -> &Ident {
+    match param {
+        GenericParam::Lifetime(l) => &l.lifetime.ident,
+        GenericParam::Type(t) => &t.ident,
+        GenericParam::Const(c) => &c.ident,
+    }
+}
+
+/// Collects the names of every identifier and lifetime in `tokens`.
+pub fn idents(tokens: TokenStream, idents_seen: &mut Vec<String>) {
+    for tree in tokens {
+        match tree {
+            proc_macro2::TokenTree::Ident(ident) => idents_seen.push(ident.to_string()),
+            proc_macro2::TokenTree::Group(group) => idents(group.stream(), idents_seen),
+            _ => (),
+        }
+    }
+}
+
+fn fill_generics(ty: &mut Type, generic: &[(String, TokenStream)]) {
+    let path = match ty {
+        Type::Path(p) if p.qself.is_none() => &mut p.path,
+        _ => return,
+    };
+
+    if path.segments.len() == 1 && path.segments[0].arguments.is_empty() {
+        let name = path.segments[0].ident.to_string();
+        if let Some((_, filled)) = generic.iter().find(|(g, _)| *g == name) {
+            *ty = parse_quote!(#filled);
+            return;
+        }
+    }
+
+    for segment in &mut path.segments {
+        if let PathArguments::AngleBracketed(args) = &mut segment.arguments {
+            for arg in &mut args.args {
+                if let GenericArgument::Type(inner) = arg {
+                    fill_generics(inner, generic);
+                }
+            }
+        }
+    }
+}
+
+/// Replaces every `Box<T>` of a new type `T` in `ty` by `TId`, adding `T`
+/// to `boxed`.
+pub fn box_to_id(ty: &mut Type, new_idents: &[String], boxed: &mut Vec<String>) {
+    let path = match ty {
+        Type::Path(p) if p.qself.is_none() => &mut p.path,
+        _ => return,
+    };
+
+    let node = match path.segments.last() {
+        Some(segment) if segment.ident == "Box" => match &segment.arguments {
+            PathArguments::AngleBracketed(args) if args.args.len() == 1 => {
+                match args.args.first() {
+                    Some(GenericArgument::Type(Type::Path(inner)))
+                        if inner.qself.is_none() && inner.path.segments.len() == 1 =>
+                    {
+                        Some(inner.path.segments[0].clone())
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        },
+        _ => None,
+    };
+
+    match node {
+        Some(node) if new_idents.contains(&node.ident.to_string()) => {
+            let id = Ident::new(&format!("{}Id", node.ident), node.ident.span());
+            if !boxed.contains(&node.ident.to_string()) {
+                boxed.push(node.ident.to_string());
+            }
+
+            *ty = parse_quote!(#id);
+        }
+        _ => {
+            for segment in &mut path.segments {
+                if let PathArguments::AngleBracketed(args) = &mut segment.arguments {
+                    for arg in &mut args.args {
+                        if let GenericArgument::Type(inner) = arg {
+                            box_to_id(inner, new_idents, boxed);
+                        }
+                    }
+                }
+            }
+        }
     }
 }
 
@@ -92,30 +723,215 @@ impl Parse for Context {
 pub struct Variant {
     pub new_type: Option<NewType>,
     pub name: Ident,
+    /// The type of a variant with a single unnamed field.
     pub ty: Option<Type>,
+    /// The fields of a variant with any other number of unnamed fields, or
+    /// with named ones.
+    pub fields: Option<VariantFields>,
+    /// The attributes before the variant's name.
+    pub attrs: Vec<Attribute>,
+}
+
+#[derive(Debug, Clone)]
+pub enum VariantFields {
+    Unnamed(Punctuated<Type, Token![,]>),
+    Named(Punctuated<Field, Token![,]>),
+}
+
+impl Variant {
+    /// The `#[cfg]` attributes of the variant, for every generated item that
+    /// names it.
+    pub fn cfg(&self) -> TokenStream {
+        cfg(&self.attrs)
+    }
+
+    /// Whether the variant has no fields at all.
+    pub fn is_unit(&self) -> bool {
+        self.ty.is_none() && self.fields.is_none()
+    }
+
+    /// The type of every field of the variant.
+    pub fn types(&self) -> Vec<&Type> {
+        match &self.fields {
+            Some(VariantFields::Unnamed(types)) => types.iter().collect(),
+            Some(VariantFields::Named(fields)) => fields.iter().map(|f| &f.ty).collect(),
+            None => self.ty.iter().collect(),
+        }
+    }
+
+    /// Removes the new types declared in the variant and its named fields,
+    /// flattened.
+    fn take_new_types(&mut self) -> Vec<NewType> {
+        let mut types = Vec::new();
+
+        if let Some(nt) = &mut self.new_type {
+            types.append(&mut flatten(nt));
+        }
+        self.new_type = None;
+
+        if let Some(VariantFields::Named(fields)) = &mut self.fields {
+            for field in fields {
+                if let Some(nt) = &mut field.new_type {
+                    types.append(&mut flatten(nt));
+                }
+
+                field.new_type = None;
+            }
+        }
+
+        types
+    }
+
+    fn types_mut(&mut self) -> Vec<&mut Type> {
+        match &mut self.fields {
+            Some(VariantFields::Unnamed(types)) => types.iter_mut().collect(),
+            Some(VariantFields::Named(fields)) => fields.iter_mut().map(|f| &mut f.ty).collect(),
+            None => self.ty.iter_mut().collect(),
+        }
+    }
+
+    /// The name bound to each field of a variant with `fields` by `pattern`,
+    /// `v0`, `v1` and so on or the field's own, and its type.
+    pub fn bindings(&self) -> Vec<(Ident, &Type)> {
+        match &self.fields {
+            Some(VariantFields::Unnamed(types)) => types
+                .iter()
+                .enumerate()
+                .map(|(i, ty)| (format_ident!("v{}", i), ty))
+                .collect(),
+            Some(VariantFields::Named(fields)) => {
+                fields.iter().map(|f| (f.ident.clone(), &f.ty)).collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// A pattern matching the variant of `owner`, binding the fields of a
+    /// variant with `fields` by `bindings`.
+    pub fn pattern(&self, owner: &Ident) -> TokenStream {
+        self.pattern_binding(owner, |_| true)
+    }
+
+    /// A match arm for a variant with `fields`, running the statements that
+    /// `visit` gives for each field's type and binding, or `None` if there
+    /// are none. Only the fields with statements are bound.
+    pub fn fields_arm(
+        &self,
+        owner: &Ident,
+        visit: impl Fn(&Type, TokenStream) -> TokenStream,
+    ) -> Option<TokenStream> {
+        let statements: Vec<(Ident, TokenStream)> = self
+            .bindings()
+            .into_iter()
+            .map(|(binding, ty)| {
+                let statements = visit(ty, quote! { #binding });
+                (binding, statements)
+            })
+            .filter(|(_, statements)| !statements.is_empty())
+            .collect();
+        if statements.is_empty() {
+            return None;
+        }
+
+        let pattern = self.pattern_binding(owner, |binding| {
+            statements.iter().any(|(bound, _)| bound == binding)
+        });
+        let statements = statements.iter().map(|(_, statements)| statements);
+
+        Some(quote! { #pattern => { #(#statements)* } })
+    }
+
+    /// Like `pattern`, but only binding the fields whose binding is `bound`.
+    pub fn pattern_binding(&self, owner: &Ident, bound: impl Fn(&Ident) -> bool) -> TokenStream {
+        let name = &self.name;
+        let bindings = self.bindings().into_iter().map(|(binding, _)| {
+            if bound(&binding) {
+                quote! { #binding }
+            } else {
+                match &self.fields {
+                    Some(VariantFields::Named(_)) => quote! { #binding: _ },
+                    _ => quote! { _ },
+                }
+            }
+        });
+
+        match &self.fields {
+            Some(VariantFields::Unnamed(_)) => quote! { #owner::#name(#(#bindings),*) },
+            Some(VariantFields::Named(_)) => quote! { #owner::#name { #(#bindings),* } },
+            None if self.ty.is_some() => quote! { #owner::#name(v) },
+            None => quote! { #owner::#name },
+        }
+    }
+}
+
+/// Gives `variant` an annotation of type `ty` after its fields, unless it
+/// holds a node of its own, whose annotation it has.
+fn annotate_variant(variant: &mut Variant, ty: &Type, new_idents: &HashSet<String>) {
+    match (&variant.ty, &mut variant.fields) {
+        (Some(inner), None) => {
+            if let Shape::Node(_) = Shape::of(inner, new_idents) {
+                return;
+            }
+
+            let types = vec![inner.clone(), ty.clone()];
+            variant.fields = Some(VariantFields::Unnamed(types.into_iter().collect()));
+            variant.ty = None;
+        }
+        (_, Some(VariantFields::Unnamed(types))) => types.push(ty.clone()),
+        (_, Some(VariantFields::Named(fields))) => fields.push(Field {
+            new_type: None,
+            vis: Visibility::Inherited,
+            ident: Ident::new("annotation", Span::call_site()),
+            ty: ty.clone(),
+            attrs: Vec::new(),
+        }),
+        (None, None) => {
+            variant.fields = Some(VariantFields::Unnamed(
+                std::iter::once(ty.clone()).collect(),
+            ))
+        }
+    }
 }
 
 impl ToTokens for Variant {
     fn to_tokens(&self, tokens: &mut TokenStream) {
         let name = &self.name;
-
-        if let Some(ty) = &self.ty {
-            tokens.append_all(quote! {
+        let attrs = &self.attrs;
+
+        match (&self.ty, &self.fields) {
+            (_, Some(VariantFields::Unnamed(types))) => tokens.append_all(quote! {
+                #(#attrs)*
+                #name(#types)
+            }),
+            (_, Some(VariantFields::Named(fields))) => tokens.append_all(quote! {
+                #(#attrs)*
+                #name { #fields }
+            }),
+            (Some(ty), None) => tokens.append_all(quote! {
+                #(#attrs)*
                 #name(#ty)
-            })
-        } else {
-            tokens.append_all(quote! {
+            }),
+            (None, None) => tokens.append_all(quote! {
+                #(#attrs)*
                 #name
-            })
+            }),
         }
     }
 }
 
+fn cfg(attrs: &[Attribute]) -> TokenStream {
+    let cfgs = attrs.iter().filter(|attr| attr.path.is_ident("cfg"));
+
+    quote! { #(#cfgs)* }
+}
+
 impl Parse for Variant {
     fn parse(input: ParseStream) -> Result<Self> {
+        let attrs = input.call(Attribute::parse_outer)?;
         let mut new_type = None;
         let name: Ident;
         let mut ty: Option<Type> = None;
+        let mut fields = None;
 
         // Shorthand Syntax |Lit|,
         let lookahead = input.lookahead1();
@@ -137,18 +953,38 @@ impl Parse for Variant {
                 ty = Some(parse_quote!(#new_name));
                 new_type = Some(parsed_new_type);
             } else if lookahead.peek(Paren) {
-                // Standard Variant syntax
+                // Standard Variant syntax, or a tuple variant with other
+                // than one field
                 let content;
                 parenthesized!(content in input);
-                ty = Some(content.parse::<Type>()?);
+                let types = content.parse_terminated(Type::parse)?;
+
+                if types.len() == 1 && !types.trailing_punct() {
+                    ty = types.into_iter().next();
+                } else {
+                    fields = Some(VariantFields::Unnamed(types));
+                }
+            } else if lookahead.peek(Brace) {
+                // Struct-like variant syntax
+                let content;
+                braced!(content in input);
+                fields = Some(VariantFields::Named(
+                    content.parse_terminated(Field::parse)?,
+                ));
             } else if lookahead.peek(Token![|]) {
                 // Variant shorthand for Wrapper struct shorthand
                 // If the Variant is the same name as the wrapper
                 input.parse::<Token![|]>()?;
 
                 new_type = Some(NewType::WrapperStruct(WrapperStruct {
+                    vis: parse_quote! { pub },
                     name: name.clone(),
+                    generics: Generics::default(),
+                    inner_vis: input.parse::<Visibility>()?,
                     ty: input.parse::<Type>()?,
+                    spanned: false,
+                    annotation: None,
+                    attrs: Vec::new(),
                 }));
 
                 ty = Some(parse_quote!(#name));
@@ -158,33 +994,68 @@ impl Parse for Variant {
             return Err(lookahead.error());
         }
 
-        Ok(Variant { new_type, name, ty })
+        let empty = match &fields {
+            Some(VariantFields::Unnamed(types)) => types.is_empty(),
+            Some(VariantFields::Named(fields)) => fields.is_empty(),
+            None => false,
+        };
+        if empty {
+            return Err(syn::Error::new_spanned(
+                &name,
+                "a variant without fields is written without brackets",
+            ));
+        }
+
+        Ok(Variant {
+            new_type,
+            name,
+            ty,
+            fields,
+            attrs,
+        })
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct Field {
     pub new_type: Option<NewType>,
+    /// Private unless written otherwise.
+    pub vis: Visibility,
     pub ident: Ident,
     pub ty: Type,
+    /// The attributes before the field's name.
+    pub attrs: Vec<Attribute>,
+}
+
+impl Field {
+    /// The `#[cfg]` attributes of the field, for every generated item that
+    /// names it.
+    pub fn cfg(&self) -> TokenStream {
+        cfg(&self.attrs)
+    }
 }
 
 impl ToTokens for Field {
     fn to_tokens(&self, tokens: &mut TokenStream) {
+        let vis = &self.vis;
         let id = &self.ident;
         let ty = &self.ty;
+        let attrs = &self.attrs;
 
         tokens.append_all(quote! {
-            #id: #ty
+            #(#attrs)*
+            #vis #id: #ty
         });
     }
 }
 
 impl Parse for Field {
     fn parse(input: ParseStream) -> Result<Self> {
+        let attrs = input.call(Attribute::parse_outer)?;
         let mut new_type = None;
         let ty;
 
+        let vis = input.parse::<Visibility>()?;
         let ident = input.parse::<Ident>()?;
 
         input.parse::<Token![:]>()?;
@@ -194,8 +1065,10 @@ impl Parse for Field {
 
             Ok(Field {
                 new_type,
+                vis,
                 ident,
                 ty,
+                attrs,
             })
         } else {
             let nt = input.call(NewType::parse)?;
@@ -206,14 +1079,17 @@ impl Parse for Field {
 
             Ok(Field {
                 new_type,
+                vis,
                 ident,
                 ty,
+                attrs,
             })
         }
     }
 }
 
 #[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum NewType {
     Enum(EnumType),
     Struct(StructType),
@@ -228,10 +1104,76 @@ impl NewType {
             Self::WrapperStruct(w) => &w.name,
         }
     }
+
+    pub fn generics(&self) -> &Generics {
+        match self {
+            Self::Enum(e) => &e.generics,
+            Self::Struct(s) => &s.generics,
+            Self::WrapperStruct(w) => &w.generics,
+        }
+    }
 }
 
 impl Parse for NewType {
     fn parse(input: ParseStream) -> Result<Self> {
+        let attrs = without_derives(input.call(Attribute::parse_outer)?, &["Debug", "Clone"])?;
+        let vis = match input.parse::<Visibility>()? {
+            Visibility::Inherited => parse_quote! { pub },
+            vis => vis,
+        };
+        let mut new_type = Self::parse_bare(input)?;
+
+        match &mut new_type {
+            NewType::Enum(e) => {
+                e.vis = vis;
+                e.attrs = attrs;
+            }
+            NewType::Struct(s) => {
+                s.vis = vis;
+                s.attrs = attrs;
+            }
+            NewType::WrapperStruct(w) => {
+                w.vis = vis;
+                w.attrs = attrs;
+            }
+        }
+
+        Ok(new_type)
+    }
+}
+
+/// `attrs` without the `derives` every new type has already, in each
+/// `#[derive]` that names them.
+fn without_derives(attrs: Vec<Attribute>, derives: &[&str]) -> Result<Vec<Attribute>> {
+    let mut kept = Vec::new();
+
+    for mut attr in attrs {
+        if !attr.path.is_ident("derive") {
+            kept.push(attr);
+            continue;
+        }
+
+        let paths = attr.parse_args_with(Punctuated::<Path, Token![,]>::parse_terminated)?;
+        let paths: Vec<Path> = paths
+            .into_iter()
+            .filter(|path| match path.segments.last() {
+                Some(last) => !derives.iter().any(|derive| last.ident == derive),
+                None => true,
+            })
+            .collect();
+
+        if !paths.is_empty() {
+            attr.tokens = quote! { (#(#paths),*) };
+            kept.push(attr);
+        }
+    }
+
+    Ok(kept)
+}
+
+impl NewType {
+    /// A declaration after its attributes.
+    fn parse_bare(input: ParseStream) -> Result<Self> {
         let lookahead = input.lookahead1();
 
         if lookahead.peek(Token![struct]) {
@@ -240,7 +1182,7 @@ impl Parse for NewType {
         } else if lookahead.peek(Token![enum]) {
             let new_enum = input.call(EnumType::parse)?;
             Ok(NewType::Enum(new_enum))
-        } else if lookahead.peek(Ident) && input.peek2(Token![|]) {
+        } else if lookahead.peek(Ident) && (input.peek2(Token![|]) || input.peek2(Token![<])) {
             let new_wrapper = input.call(WrapperStruct::parse)?;
             Ok(NewType::WrapperStruct(new_wrapper))
         } else {
@@ -251,59 +1193,120 @@ impl Parse for NewType {
 
 #[derive(Debug, Clone)]
 pub struct EnumType {
+    /// Public unless written otherwise.
+    pub vis: Visibility,
     pub name: Ident,
+    pub generics: Generics,
     pub variants: Punctuated<Variant, Token![,]>,
+    /// The type of the annotation, if there is one. A variant holding a node
+    /// of its own has that node's, and every other variant carries one after
+    /// its fields.
+    pub annotation: Option<Ident>,
+    /// Emitted after the derives.
+    pub attrs: Vec<Attribute>,
 }
 
 impl Parse for EnumType {
     fn parse(input: ParseStream) -> Result<Self> {
         input.parse::<Token![enum]>()?;
         let name = input.parse::<Ident>()?;
+        let mut generics = input.parse::<Generics>()?;
+        generics.where_clause = input.parse::<Option<WhereClause>>()?;
 
         let inner;
         braced!(inner in input);
 
         let variants = inner.parse_terminated(Variant::parse)?;
 
-        Ok(EnumType { name, variants })
+        Ok(EnumType {
+            vis: Visibility::Inherited,
+            name,
+            generics,
+            variants,
+            annotation: None,
+            attrs: Vec::new(),
+        })
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct StructType {
+    /// Public unless written otherwise.
+    pub vis: Visibility,
     pub name: Ident,
+    pub generics: Generics,
     pub fields: Punctuated<Field, Token![,]>,
+    /// Whether there is a `span` field besides `fields`.
+    pub spanned: bool,
+    /// The type of the `annotation` field after `fields`, if there is one.
+    pub annotation: Option<Ident>,
+    /// Emitted after the derives.
+    pub attrs: Vec<Attribute>,
 }
 
 impl Parse for StructType {
     fn parse(input: ParseStream) -> Result<Self> {
         input.parse::<Token![struct]>()?;
         let name = input.parse::<Ident>()?;
+        let mut generics = input.parse::<Generics>()?;
+        generics.where_clause = input.parse::<Option<WhereClause>>()?;
 
         let inner;
         braced!(inner in input);
 
         let fields = inner.parse_terminated(Field::parse)?;
 
-        Ok(StructType { name, fields })
+        Ok(StructType {
+            vis: Visibility::Inherited,
+            name,
+            generics,
+            fields,
+            spanned: false,
+            annotation: None,
+            attrs: Vec::new(),
+        })
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct WrapperStruct {
+    /// Public unless written otherwise.
+    pub vis: Visibility,
     pub name: Ident,
+    pub generics: Generics,
+    /// That of the inner value, private unless written otherwise.
+    pub inner_vis: Visibility,
     pub ty: Type,
+    /// Whether there is a span after the inner value.
+    pub spanned: bool,
+    /// The type of the annotation after the inner value and span, if there
+    /// is one.
+    pub annotation: Option<Ident>,
+    /// Emitted after the derives.
+    pub attrs: Vec<Attribute>,
 }
 
 impl Parse for WrapperStruct {
     fn parse(input: ParseStream) -> Result<Self> {
         let name = input.parse::<Ident>()?;
+        let mut generics = input.parse::<Generics>()?;
+        generics.where_clause = input.parse::<Option<WhereClause>>()?;
 
         input.parse::<Token![|]>()?;
+        let inner_vis = input.parse::<Visibility>()?;
         let ty = input.parse::<Type>()?;
         input.parse::<Token![|]>()?;
 
-        Ok(WrapperStruct { name, ty })
+        Ok(WrapperStruct {
+            vis: Visibility::Inherited,
+            name,
+            generics,
+            inner_vis,
+            ty,
+            spanned: false,
+            annotation: None,
+            attrs: Vec::new(),
+        })
     }
 }
 
@@ -319,52 +1322,104 @@ impl ToTokens for NewType {
 
 impl ToTokens for EnumType {
     fn to_tokens(&self, tokens: &mut TokenStream) {
-        let Self { name, variants, .. } = self;
+        let Self {
+            vis,
+            name,
+            generics,
+            variants,
+            attrs,
+            ..
+        } = self;
+        let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
 
         let variant_iter = variants.iter();
 
         // let (names, types): (Vec<&Ident>, Vec<&Option<Type>>) = variants.iter().map(|v| (&v.name, &v.ty)).unzip();
-        let (typed, raw): (Vec<&Variant>, Vec<&Variant>) =
-            variants.iter().partition(|v| v.ty.is_some());
+        let typed: Vec<&Variant> = variants.iter().filter(|v| v.ty.is_some()).collect();
+        let raw: Vec<&Variant> = variants.iter().filter(|v| v.is_unit()).collect();
         // let (typed, raw) = (typed.iter().map(|v| &v.name), raw.iter().map(|v| &v.name));
         let (typed_names, types): (Vec<&Ident>, Vec<Type>) = typed
             .iter()
             .map(|v| (&v.name, v.ty.clone().unwrap()))
             .unzip();
+        let typed_cfgs: Vec<TokenStream> = typed.iter().map(|v| v.cfg()).collect();
 
-        let typed_names_lower = typed_names.iter().map(|i| {
-            let lower = i.to_string().to_lowercase();
-            Ident::new(&lower, i.span())
-        });
+        let typed_names_lower = typed_names.iter().map(|i| lowercase(i));
 
         let raw_names = raw.iter().map(|r| &r.name);
-        let raw_names_lower = raw_names.clone().map(|i| {
-            let lower = i.to_string().to_lowercase();
-            Ident::new(&lower, i.span())
+        let raw_cfgs = raw.iter().map(|r| r.cfg());
+        let raw_names_lower = raw_names.clone().map(lowercase);
+
+        // Variants with several fields or named ones take each field, and
+        // tuples of several can be built `From` the tuple of their fields
+        let compound: Vec<&Variant> = variants.iter().filter(|v| v.fields.is_some()).collect();
+        let compound_constructors = compound.iter().map(|v| {
+            let cfg = v.cfg();
+            let constructor = lowercase(&v.name);
+            let (bindings, types): (Vec<Ident>, Vec<&Type>) = v.bindings().into_iter().unzip();
+            let pattern = v.pattern(name);
+
+            quote! {
+                #cfg
+                pub fn #constructor(#(#bindings: impl Into<#types>),*) -> Self {
+                    #(let #bindings = #bindings.into();)*
+                    #pattern
+                }
+            }
+        });
+        let tuple_froms = compound.iter().filter_map(|v| match &v.fields {
+            Some(VariantFields::Unnamed(types)) if types.len() > 1 => {
+                let cfg = v.cfg();
+                let bindings = v.bindings().into_iter().map(|(binding, _)| binding);
+                let pattern = v.pattern(name);
+                let tuple = quote! { (#types) };
+
+                Some(quote! {
+                    #cfg
+                    impl #impl_generics From<#tuple> for #name #ty_generics #where_clause {
+                        fn from((#(#bindings),*): #tuple) -> Self {
+                            #pattern
+                        }
+                    }
+                })
+            }
+            _ => None,
         });
 
+        let accessors = enum_annotation_accessors(self);
+
         tokens.append_all(quote! {
             #[derive(Debug, Clone)]
-            pub enum #name {
+            #(#attrs)*
+            #vis enum #name #impl_generics #where_clause {
                 #(#variant_iter),*
             }
 
-            impl #name {
+            impl #impl_generics #name #ty_generics #where_clause {
                 #(
+                    #typed_cfgs
                     pub fn #typed_names_lower<T: Into<#types>>(e: T) -> Self {
                         #name::#typed_names(e.into())
                     }
                 )*
 
                 #(
+                    #raw_cfgs
                     pub fn #raw_names_lower() -> Self {
                         #name::#raw_names
                     }
                 )*
+
+                #(#compound_constructors)*
+
+                #accessors
             }
 
+            #(#tuple_froms)*
+
             #(
-                impl From<#types> for #name {
+                #typed_cfgs
+                impl #impl_generics From<#types> for #name #ty_generics #where_clause {
                     fn from(v: #types) -> Self {
                         Self::#typed_names(v)
                     }
@@ -376,10 +1431,19 @@ impl ToTokens for EnumType {
 
 impl ToTokens for StructType {
     fn to_tokens(&self, tokens: &mut TokenStream) {
-        let Self { name, fields, .. } = self;
+        let Self {
+            vis,
+            name,
+            generics,
+            fields,
+            attrs,
+            ..
+        } = self;
+        let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
 
         let field_names: Vec<&Ident> = fields.iter().map(|f| &f.ident).collect();
         let field_types: Vec<&Type> = fields.iter().map(|f| &f.ty).collect();
+        let field_cfgs: Vec<TokenStream> = fields.iter().map(|f| f.cfg()).collect();
         let field_names_mut: Vec<Ident> = fields
             .iter()
             .map(|f| {
@@ -388,26 +1452,56 @@ impl ToTokens for StructType {
             })
             .collect();
 
+        let fields = fields.iter();
+
+        let (span_field, span_param, span_init) = if self.spanned {
+            (
+                quote! { span: IgnoredSpan, },
+                quote! { span: Span, },
+                quote! { span: IgnoredSpan(span), },
+            )
+        } else {
+            (TokenStream::new(), TokenStream::new(), TokenStream::new())
+        };
+        let (annotation_field, annotation_param, annotation_init) = match &self.annotation {
+            Some(ann) => (
+                quote! { annotation: #ann, },
+                quote! { annotation: #ann, },
+                quote! { annotation, },
+            ),
+            None => Default::default(),
+        };
+        let accessors = annotation_accessors(self.annotation.as_ref(), quote! { self.annotation });
+
         tokens.append_all(quote! {
             #[derive(Debug, Clone)]
-            pub struct #name {
-                #(#field_names : #field_types),*
+            #(#attrs)*
+            #vis struct #name #impl_generics #where_clause {
+                #(#fields,)*
+                #span_field
+                #annotation_field
             }
 
-            impl #name {
-                pub fn new(#(#field_names : #field_types),*) -> Self {
+            impl #impl_generics #name #ty_generics #where_clause {
+                pub fn new(#(#field_cfgs #field_names : #field_types,)* #span_param #annotation_param) -> Self {
                     Self {
-                        #(#field_names),*
+                        #(#field_cfgs #field_names,)*
+                        #span_init
+                        #annotation_init
                     }
                 }
 
+                #accessors
+
                 #(
+                    #field_cfgs
                     pub fn #field_names(&self) -> &#field_types {
                         &self.#field_names
                     }
                 )*
 
                 #(
+                    #field_cfgs
                     pub fn #field_names_mut(&mut self) -> &mut #field_types {
                         &mut self.#field_names
                     }
@@ -419,18 +1513,58 @@ impl ToTokens for StructType {
 
 impl ToTokens for WrapperStruct {
     fn to_tokens(&self, tokens: &mut TokenStream) {
-        let name = &self.name;
+        let (vis, name, inner_vis) = (&self.vis, &self.name, &self.inner_vis);
         let ty = &self.ty;
+        let attrs = &self.attrs;
+        let (impl_generics, ty_generics, where_clause) = self.generics.split_for_impl();
+
+        // A wrapper built `From` its inner value gets the default span
+        let (span_field, span_param, span_init, span_default) = if self.spanned {
+            (
+                quote! { , IgnoredSpan },
+                quote! { , span: Span },
+                quote! { , IgnoredSpan(span) },
+                quote! { , IgnoredSpan::default() },
+            )
+        } else {
+            Default::default()
+        };
+
+        // And the default annotation, if it has one
+        let mut from_generics = self.generics.clone();
+        let (annotation_field, annotation_param, annotation_init, annotation_default) =
+            match &self.annotation {
+                Some(ann) => {
+                    from_generics
+                        .make_where_clause()
+                        .predicates
+                        .push(parse_quote! { #ann: Default });
+
+                    (
+                        quote! { , #ann },
+                        quote! { , annotation: #ann },
+                        quote! { , annotation },
+                        quote! { , Default::default() },
+                    )
+                }
+                None => Default::default(),
+            };
+        let (_, _, from_where) = from_generics.split_for_impl();
+        let index = syn::Index::from(if self.spanned { 2 } else { 1 });
+        let accessors = annotation_accessors(self.annotation.as_ref(), quote! { self.#index });
 
         tokens.append_all(quote! {
             #[derive(Debug, Clone)]
-            pub struct #name(#ty);
+            #(#attrs)*
+            #vis struct #name #impl_generics (#inner_vis #ty #span_field #annotation_field) #where_clause;
 
-            impl #name {
-                pub fn new(inner: #ty) -> Self {
-                    Self(inner)
+            impl #impl_generics #name #ty_generics #where_clause {
+                pub fn new(inner: #ty #span_param #annotation_param) -> Self {
+                    Self(inner #span_init #annotation_init)
                 }
 
+                #accessors
+
                 pub fn inner(&self) -> &#ty {
                     &self.0
                 }
@@ -444,15 +1578,93 @@ impl ToTokens for WrapperStruct {
                 }
             }
 
-            impl From<#ty> for #name {
+            impl #impl_generics From<#ty> for #name #ty_generics #from_where {
                 fn from(i: #ty) -> Self {
-                    Self(i)
+                    Self(i #span_default #annotation_default)
                 }
             }
         });
     }
 }
 
+/// `annotation`, `annotation_mut` and `with_annotation` for a struct or
+/// wrapper whose annotation is `field`.
+fn annotation_accessors(annotation: Option<&Ident>, field: TokenStream) -> TokenStream {
+    let ann = match annotation {
+        Some(ann) => ann,
+        None => return TokenStream::new(),
+    };
+
+    quote! {
+        pub fn annotation(&self) -> &#ann {
+            &#field
+        }
+
+        pub fn annotation_mut(&mut self) -> &mut #ann {
+            &mut #field
+        }
+
+        pub fn with_annotation(mut self, annotation: #ann) -> Self {
+            #field = annotation;
+            self
+        }
+    }
+}
+
+/// `annotation`, `annotation_mut` and `with_annotation` for an annotated
+/// enum, reaching into the node a variant holds or the annotation after its
+/// fields.
+fn enum_annotation_accessors(e: &EnumType) -> TokenStream {
+    let ann = match &e.annotation {
+        Some(ann) => ann,
+        None => return TokenStream::new(),
+    };
+
+    let (arms, arms_mut): (Vec<TokenStream>, Vec<TokenStream>) = e
+        .variants
+        .iter()
+        .map(|v| {
+            let cfg = v.cfg();
+
+            match v.bindings().last() {
+                Some((annotation, _)) => {
+                    let pattern = v.pattern_binding(&e.name, |binding| binding == annotation);
+                    let arm = quote! { #cfg #pattern => #annotation, };
+
+                    (arm.clone(), arm)
+                }
+                None => {
+                    let pattern = v.pattern(&e.name);
+
+                    (
+                        quote! { #cfg #pattern => v.annotation(), },
+                        quote! { #cfg #pattern => v.annotation_mut(), },
+                    )
+                }
+            }
+        })
+        .unzip();
+
+    quote! {
+        pub fn annotation(&self) -> &#ann {
+            match self {
+                #(#arms)*
+            }
+        }
+
+        pub fn annotation_mut(&mut self) -> &mut #ann {
+            match self {
+                #(#arms_mut)*
+            }
+        }
+
+        pub fn with_annotation(mut self, annotation: #ann) -> Self {
+            *self.annotation_mut() = annotation;
+            self
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -488,6 +1700,187 @@ mod tests {
         println!("{:#?}\n", field);
     }
 
+    #[test]
+    fn parse_attributes() {
+        let variant: Variant = parse_quote! {
+            /// A call.
+            #[cfg(feature = "calls")]
+            Call: #[derive(PartialEq)] struct Call {
+                #[cfg(feature = "named")]
+                name: String,
+            }
+        };
+
+        assert_eq!(variant.attrs.len(), 2);
+        assert_eq!(
+            variant.cfg().to_string(),
+            quote! { #[cfg(feature = "calls")] }.to_string()
+        );
+
+        let call = match variant.new_type {
+            Some(NewType::Struct(s)) => s,
+            _ => unreachable!(),
+        };
+        assert_eq!(call.attrs.len(), 1);
+        assert_eq!(call.fields[0].attrs.len(), 1);
+
+        println!("{}", quote! { #call });
+    }
+
+    #[test]
+    fn parse_settings() {
+        let context: Context = parse_quote! {
+            #![module = surface, root = _]
+            #![visitor = SurfaceVisitor]
+            Expr: enum Expr {
+                Unit,
+            }
+        };
+
+        assert_eq!(context.module, "surface");
+        assert!(context.root.is_none());
+        assert_eq!(context.visitor, "SurfaceVisitor");
+
+        let rootless = syn::parse2::<Context>(quote! {
+            #![root = _]
+            Unit,
+        });
+        assert!(rootless.is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn parse_serde() {
+        let adjacent = syn::parse2::<Context>(quote! {
+            #![serde(tag = "type", content = "value")]
+            Lit |isize|,
+            Expr: enum Expr {
+                |Lit|,
+                Neg(Box<Expr>),
+            },
+        });
+        assert!(adjacent.is_ok());
+
+        let internal = syn::parse2::<Context>(quote! {
+            #![root = _]
+            #![serde(tag = "type")]
+            Expr: enum Expr {
+                Lit: struct Lit { value: isize },
+                Neg: struct Neg { expr: Box<Expr> },
+                Call { args: Vec<Expr> },
+                Hole,
+            },
+        });
+        assert!(internal.is_ok());
+
+        let untaggable = syn::parse2::<Context>(quote! {
+            #![root = _]
+            #![serde(tag = "type")]
+            Expr: enum Expr {
+                Lit: struct Lit { value: isize },
+                Paren(Box<Expr>),
+                Pair(Box<Expr>, Box<Expr>),
+            },
+        });
+        let errors: Vec<String> = untaggable
+            .err()
+            .unwrap()
+            .into_iter()
+            .map(|e| e.to_string())
+            .collect();
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors[0].starts_with("`Paren` can't be internally tagged"));
+        assert!(errors[1].starts_with("`Pair` can't be internally tagged"));
+    }
+
+    #[test]
+    fn parse_generics() {
+        let context: Context = parse_quote! {
+            Name: Name<'src> |&'src str|,
+            Expr: enum Expr<A> where A: Clone {
+                Neg(Box<Expr>),
+                Lit(A),
+            },
+        };
+
+        let types: Vec<String> = context
+            .new_types
+            .iter()
+            .map(|nt| context.ty(nt.name()).to_string())
+            .collect();
+        assert_eq!(types, vec!["Name < 'src >", "Expr < A >"]);
+
+        let generics = &context.generics;
+        assert_eq!(quote! { #generics }.to_string(), "< 'src , A >");
+        assert_eq!(
+            context.ty(context.root.as_ref().unwrap()).to_string(),
+            "Ast < 'src , A >"
+        );
+
+        println!("{}", context.create_ast(None));
+    }
+
+    #[test]
+    fn parse_annotated() {
+        let context: Context = parse_quote! {
+            #![annotated(Info)]
+            Name: Name<'src> |&'src str|,
+            Expr: enum Expr<'src> {
+                Call: struct Call<'src> {
+                    callee: Name,
+                    op: enum Op {
+                        Plus,
+                    },
+                },
+                Neg(Box<Expr>),
+            },
+        };
+
+        let types: Vec<String> = context
+            .new_types
+            .iter()
+            .map(|nt| context.ty(nt.name()).to_string())
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                "Name < 'src , Info >",
+                "Expr < 'src , Info >",
+                "Call < 'src , Info >",
+                "Op < Info >"
+            ]
+        );
+
+        // A variant without a node of its own carries the annotation
+        match &context.new_types[1] {
+            NewType::Enum(e) => {
+                let neg = e.variants.iter().find(|v| v.name == "Neg").unwrap();
+                let types: Vec<String> = neg
+                    .types()
+                    .iter()
+                    .map(|ty| ty.to_token_stream().to_string())
+                    .collect();
+                assert_eq!(types, vec!["Box < Expr < 'src , Info > >", "Info"]);
+            }
+            nt => panic!("expected an enum, got {:?}", nt),
+        }
+
+        println!("{}", context.create_ast(None));
+
+        let arena = syn::parse2::<Context>(quote! {
+            #![arena]
+            #![annotated]
+            Expr: enum Expr {
+                Neg(Box<Expr>),
+                Unit,
+            },
+        });
+        assert_eq!(
+            arena.err().unwrap().to_string(),
+            "`#![annotated]` can't be combined with `#![arena]`"
+        );
+    }
+
     #[test]
     fn parse_context() {
         let context: Context = parse_quote! {
@@ -517,7 +1910,6 @@ mod tests {
             },
             Stmt: enum Stmt {
                 |Expr|,
-                Assignment(Assignment),
                 Assignment: struct Assignment {
                     lhs: Ident,
                     rhs: Option<Expr>,
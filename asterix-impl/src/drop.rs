@@ -0,0 +1,487 @@
+use std::collections::{HashMap, HashSet};
+
+use proc_macro2::TokenStream;
+use quote::{quote, ToTokens, TokenStreamExt};
+use syn::{Generics, Ident, Type};
+
+use crate::context::{Context, NewType, VariantFields};
+use crate::shape::Shape;
+
+/// Stack-safe `Drop` impls for the recursive new types.
+///
+/// Dropping a recursive type first detaches its recursive children onto a
+/// work list on the heap, swapping each for a placeholder without children
+/// of its own, then does the same for everything on the list. The drop glue
+/// of every node therefore only ever sees placeholders.
+pub struct Drops<'c> {
+    pub new_idents: HashSet<String>,
+    /// The new types that can contain themselves.
+    pub recursive: HashSet<String>,
+    context: &'c Context,
+}
+
+impl<'c> Drops<'c> {
+    pub fn new(context: &'c Context) -> Self {
+        let new_idents: HashSet<String> = context
+            .new_types
+            .iter()
+            .map(|n| n.name().to_string())
+            .collect();
+
+        let children: HashMap<String, Vec<String>> = context
+            .new_types
+            .iter()
+            .map(|nt| (nt.name().to_string(), child_types(nt, &new_idents)))
+            .collect();
+
+        let recursive = new_idents
+            .iter()
+            .filter(|name| reaches(&children, name, name))
+            .cloned()
+            .collect();
+
+        Self {
+            new_idents,
+            recursive,
+            context,
+        }
+    }
+
+    /// Whether `name` is given a `Drop` impl. Wrappers never are, so that
+    /// `into_inner` can move out of them; their cycles are torn down by the
+    /// structs and enums on them.
+    pub fn has_drop(&self, name: &Ident) -> bool {
+        self.context.stack_safe_drop
+            && self.recursive.contains(&name.to_string())
+            && self
+                .context
+                .new_types
+                .iter()
+                .any(|nt| nt.name() == name && !matches!(nt, NewType::WrapperStruct(_)))
+    }
+
+    pub fn create_drops(&self) -> TokenStream {
+        let mut tokens = TokenStream::default();
+
+        if !self.context.stack_safe_drop || self.recursive.is_empty() {
+            return tokens;
+        }
+
+        let recursive: Vec<&NewType> = self
+            .context
+            .new_types
+            .iter()
+            .filter(|nt| self.recursive.contains(&nt.name().to_string()))
+            .collect();
+        let names: Vec<&Ident> = recursive.iter().map(|nt| nt.name()).collect();
+        let types: Vec<TokenStream> = names.iter().map(|n| self.context.ty(n)).collect();
+
+        // Every recursive type holds the others, so they are all on the work
+        // list together and must agree on their parameters
+        let generics = recursive[0].generics();
+        let same = |g: &Generics| {
+            g.to_token_stream().to_string() == generics.to_token_stream().to_string()
+                && g.where_clause.to_token_stream().to_string()
+                    == generics.where_clause.to_token_stream().to_string()
+        };
+        if let Some(other) = recursive.iter().find(|nt| !same(nt.generics())) {
+            return syn::Error::new_spanned(
+                other.name(),
+                format!(
+                    "`stack_safe_drop` needs the recursive types `{}` and `{}` to have the same generic parameters",
+                    recursive[0].name(),
+                    other.name(),
+                ),
+            )
+            .to_compile_error();
+        }
+        let (params, args, where_clause) = generics.split_for_impl();
+
+        tokens.append_all(quote! {
+            /// A node waiting on the drop work list.
+            enum Detached #params #where_clause {
+                #(#names(#types)),*
+            }
+
+            impl #params Detached #args #where_clause {
+                fn detach(&mut self, stack: &mut Vec<Detached #args>) {
+                    match self {
+                        #(
+                            Detached::#names(node) => node.detach(stack),
+                        )*
+                    }
+                }
+            }
+        });
+
+        for new_type in recursive {
+            let name = new_type.name();
+            let detach = self.detach(new_type);
+
+            tokens.append_all(quote! {
+                impl #params #name #args #where_clause {
+                    /// Moves every recursive child onto `stack`, leaving
+                    /// placeholders behind.
+                    fn detach(&mut self, stack: &mut Vec<Detached #args>) {
+                        #detach
+                    }
+                }
+            });
+
+            if self.has_drop(name) {
+                tokens.append_all(quote! {
+                    impl #params Drop for #name #args #where_clause {
+                        fn drop(&mut self) {
+                            let mut stack = Vec::new();
+                            self.detach(&mut stack);
+
+                            while let Some(mut node) = stack.pop() {
+                                node.detach(&mut stack);
+                            }
+                        }
+                    }
+                });
+            }
+        }
+
+        tokens
+    }
+
+    /// Statements detaching the recursive nodes reachable through the
+    /// mutable reference `expr`.
+    fn push(&self, ty: &Type, expr: TokenStream) -> TokenStream {
+        let shape = Shape::of(ty, &self.new_idents);
+        let inline = matches!(shape, Shape::Node(_));
+
+        // The nodes behind ids belong to the arena, and are not followed
+        shape.visit(expr, true, &TokenStream::new(), &|node, expr| {
+            if !self.recursive.contains(&node.to_string()) {
+                return TokenStream::new();
+            }
+
+            let placeholder = match self.placeholder(node, &mut Vec::new()) {
+                Some(placeholder) => placeholder,
+                // A node held inline, or one whose type can't come back to
+                // itself through others without placeholders, can be
+                // detached from where it is, which goes no deeper than its
+                // type is nested
+                None if inline || self.in_place(node) => return quote! { (#expr).detach(stack); },
+                None => {
+                    let message = if self.context.annotation.is_some() {
+                        format!(
+                            "`stack_safe_drop` needs a value of `{}` without children to leave in place of a child, and every value of an annotated type holds an annotation",
+                            node,
+                        )
+                    } else {
+                        format!(
+                            "`stack_safe_drop` needs a value of `{}` without children or leaf fields to leave in place of a child, like a variant without fields",
+                            node,
+                        )
+                    };
+                    return syn::Error::new(node.span(), message).to_compile_error();
+                }
+            };
+
+            quote! {
+                stack.push(Detached::#node(std::mem::replace(#expr, #placeholder)));
+            }
+        })
+    }
+
+    fn detach(&self, new_type: &NewType) -> TokenStream {
+        match new_type {
+            NewType::Enum(e) => {
+                let name = &e.name;
+                let arms = e.variants.iter().map(|v| {
+                    let variant = &v.name;
+                    let cfg = v.cfg();
+
+                    if v.fields.is_some() {
+                        return match v.fields_arm(name, |ty, expr| self.push(ty, expr)) {
+                            Some(arm) => quote! { #cfg #arm },
+                            None => quote! { #cfg #name::#variant { .. } => {} },
+                        };
+                    }
+
+                    match &v.ty {
+                        Some(ty) => {
+                            let push = self.push(ty, quote! { v });
+                            if push.is_empty() {
+                                quote! { #cfg #name::#variant(_) => {} }
+                            } else {
+                                quote! { #cfg #name::#variant(v) => { #push } }
+                            }
+                        }
+                        None => quote! { #cfg #name::#variant => {} },
+                    }
+                });
+
+                quote! {
+                    match self {
+                        #(#arms)*
+                    }
+                }
+            }
+            NewType::Struct(s) => {
+                let pushes = s.fields.iter().map(|f| {
+                    let ident = &f.ident;
+                    let cfg = f.cfg();
+                    let push = self.push(&f.ty, quote! { &mut self.#ident });
+
+                    if cfg.is_empty() || push.is_empty() {
+                        push
+                    } else {
+                        quote! { #cfg { #push } }
+                    }
+                });
+
+                quote! { #(#pushes)* }
+            }
+            NewType::WrapperStruct(w) => self.push(&w.ty, quote! { &mut self.0 }),
+        }
+    }
+
+    /// Whether the recursive types without placeholders that `name` reaches
+    /// never lead back to it.
+    fn in_place(&self, name: &Ident) -> bool {
+        let bare: Vec<&NewType> = self
+            .context
+            .new_types
+            .iter()
+            .filter(|nt| {
+                self.recursive.contains(&nt.name().to_string())
+                    && self.placeholder(nt.name(), &mut Vec::new()).is_none()
+            })
+            .collect();
+        let names: Vec<String> = bare.iter().map(|nt| nt.name().to_string()).collect();
+
+        let children: HashMap<String, Vec<String>> = bare
+            .iter()
+            .map(|nt| {
+                let children = child_types(nt, &self.new_idents)
+                    .into_iter()
+                    .filter(|child| names.contains(child))
+                    .collect();
+
+                (nt.name().to_string(), children)
+            })
+            .collect();
+
+        !reaches(&children, &name.to_string(), &name.to_string())
+    }
+
+    /// The cheapest value of the new type `name` that contains no nodes of the
+    /// types in `visiting`, or `None` if every value does. It is built from
+    /// raw variants, `None` and empty `Vec`s, and never holds a leaf, whose
+    /// type need not have a `Default`, nor an annotation. Variants that may be
+    /// configured away are never used.
+    fn placeholder(&self, name: &Ident, visiting: &mut Vec<String>) -> Option<TokenStream> {
+        if visiting.contains(&name.to_string()) {
+            return None;
+        }
+
+        let new_type = self.context.new_types.iter().find(|nt| nt.name() == name)?;
+        visiting.push(name.to_string());
+
+        let placeholder = match new_type {
+            NewType::Enum(e) => {
+                let mut variants = e.variants.iter().filter(|v| v.cfg().is_empty());
+
+                match variants.clone().find(|v| v.is_unit()) {
+                    Some(raw) => {
+                        let variant = &raw.name;
+                        Some(quote! { #name::#variant })
+                    }
+                    None => variants.find_map(|v| {
+                        let variant = &v.name;
+
+                        match &v.fields {
+                            Some(VariantFields::Unnamed(types)) => {
+                                let values = types
+                                    .iter()
+                                    .map(|ty| self.placeholder_of(ty, visiting))
+                                    .collect::<Option<Vec<TokenStream>>>()?;
+
+                                Some(quote! { #name::#variant(#(#values),*) })
+                            }
+                            Some(VariantFields::Named(fields)) => {
+                                let values = fields
+                                    .iter()
+                                    .map(|f| {
+                                        let ident = &f.ident;
+                                        let value = self.placeholder_of(&f.ty, visiting)?;
+
+                                        Some(quote! { #ident: #value })
+                                    })
+                                    .collect::<Option<Vec<TokenStream>>>()?;
+
+                                Some(quote! { #name::#variant { #(#values),* } })
+                            }
+                            None => {
+                                let inner = self.placeholder_of(v.ty.as_ref()?, visiting)?;
+
+                                Some(quote! { #name::#variant(#inner) })
+                            }
+                        }
+                    }),
+                }
+            }
+            NewType::Struct(s) if s.annotation.is_some() => None,
+            NewType::WrapperStruct(w) if w.annotation.is_some() => None,
+            NewType::Struct(s) => s
+                .fields
+                .iter()
+                .map(|f| {
+                    let ident = &f.ident;
+                    let cfg = f.cfg();
+                    let value = self.placeholder_of(&f.ty, visiting)?;
+
+                    Some(quote! { #cfg #ident: #value })
+                })
+                .collect::<Option<Vec<TokenStream>>>()
+                .map(|mut fields| {
+                    if s.spanned {
+                        fields.push(quote! { span: Default::default() });
+                    }
+
+                    quote! { #name { #(#fields),* } }
+                }),
+            NewType::WrapperStruct(w) => self.placeholder_of(&w.ty, visiting).map(|inner| {
+                if w.spanned {
+                    quote! { #name(#inner, Default::default()) }
+                } else {
+                    quote! { #name(#inner) }
+                }
+            }),
+        };
+
+        visiting.pop();
+        placeholder
+    }
+
+    fn placeholder_of(&self, ty: &Type, visiting: &mut Vec<String>) -> Option<TokenStream> {
+        self.placeholder_shape(&Shape::of(ty, &self.new_idents), visiting)
+    }
+
+    fn placeholder_shape(&self, shape: &Shape, visiting: &mut Vec<String>) -> Option<TokenStream> {
+        match shape {
+            Shape::Node(name) => self.placeholder(name, visiting),
+            Shape::Box(inner) => self
+                .placeholder_shape(inner, visiting)
+                .map(|inner| quote! { Box::new(#inner) }),
+            Shape::Option(_) => Some(quote! { None }),
+            Shape::Vec(_) => Some(quote! { Vec::new() }),
+            Shape::Id(_) | Shape::Leaf => None,
+        }
+    }
+}
+
+/// The names of the new types directly reachable from `new_type`.
+fn child_types(new_type: &NewType, new_idents: &HashSet<String>) -> Vec<String> {
+    let types: Vec<&Type> = match new_type {
+        NewType::Enum(e) => e.variants.iter().flat_map(|v| v.types()).collect(),
+        NewType::Struct(s) => s.fields.iter().map(|f| &f.ty).collect(),
+        NewType::WrapperStruct(w) => vec![&w.ty],
+    };
+
+    let mut children = Vec::new();
+    for ty in types {
+        let mut shape = Shape::of(ty, new_idents);
+        loop {
+            shape = match shape {
+                Shape::Node(name) => {
+                    children.push(name.to_string());
+                    break;
+                }
+                Shape::Box(inner) | Shape::Option(inner) | Shape::Vec(inner) => *inner,
+                Shape::Id(_) | Shape::Leaf => break,
+            };
+        }
+    }
+
+    children
+}
+
+/// Whether `to` can be reached from the children of `from`.
+fn reaches(children: &HashMap<String, Vec<String>>, from: &str, to: &str) -> bool {
+    let mut seen = HashSet::new();
+    let mut stack: Vec<&str> = children
+        .get(from)
+        .into_iter()
+        .flatten()
+        .map(|c| c.as_str())
+        .collect();
+
+    while let Some(name) = stack.pop() {
+        if name == to {
+            return true;
+        }
+
+        if seen.insert(name) {
+            stack.extend(children.get(name).into_iter().flatten().map(|c| c.as_str()));
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    #[test]
+    fn recursive_types() {
+        let context: Context = parse_quote! {
+            #![stack_safe_drop]
+            Lit |isize|,
+            Expr: enum Expr {
+                Neg(Box<Expr>),
+                Call: struct Call {
+                    args: Vec<Expr>,
+                },
+                |Lit|,
+            },
+            Stmt: enum Stmt {
+                |Expr|,
+                Block |Vec<Stmt>|,
+            }
+        };
+
+        let drops = Drops::new(&context);
+        let mut recursive: Vec<&str> = drops.recursive.iter().map(|r| r.as_str()).collect();
+        recursive.sort();
+
+        assert_eq!(recursive, vec!["Block", "Call", "Expr", "Stmt"]);
+
+        println!("{}", drops.create_drops());
+    }
+
+    #[test]
+    fn placeholders() {
+        // A leaf field would need a `Default`, so the variant without one is
+        // used
+        let context: Context = parse_quote! {
+            #![stack_safe_drop]
+            Expr: enum Expr {
+                Lit(isize),
+                Call(Vec<Expr>),
+                Neg(Box<Expr>),
+            }
+        };
+        let drops = Drops::new(&context).create_drops().to_string();
+        assert!(drops.contains("Expr :: Call ( Vec :: new ( ) )"));
+        assert!(!drops.contains("Default"));
+
+        let context: Context = parse_quote! {
+            #![stack_safe_drop]
+            Expr: enum Expr {
+                Raw(super::NoDefault),
+                Neg(Box<Expr>),
+            }
+        };
+        let drops = Drops::new(&context).create_drops().to_string();
+        assert!(drops.contains("compile_error"));
+        assert!(!drops.contains("Default"));
+    }
+}
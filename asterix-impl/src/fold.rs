@@ -0,0 +1,633 @@
+use std::collections::HashSet;
+
+use proc_macro2::{Group, Span, TokenStream, TokenTree};
+use quote::{format_ident, quote, ToTokens, TokenStreamExt};
+use syn::{parse_quote, GenericParam, Generics, Ident, Type};
+
+use crate::context::{lowercase, param_ident, Context, NewType};
+use crate::drop::Drops;
+use crate::shape::Shape;
+
+/// `Fold`, which rebuilds a tree, or in annotated mode `MapAnnotations`,
+/// which rebuilds it with annotations of another type.
+pub struct Fold<'c> {
+    pub new_idents: HashSet<String>,
+    context: &'c Context,
+    drops: Drops<'c>,
+    /// The annotation parameter and the one it is mapped to, for
+    /// `MapAnnotations`.
+    map: Option<(Ident, Ident)>,
+}
+
+impl<'c> Fold<'c> {
+    pub fn new(context: &'c Context) -> Self {
+        let new_idents: HashSet<String> = context
+            .new_types
+            .iter()
+            .map(|n| n.name().to_string())
+            .collect();
+
+        Self {
+            new_idents,
+            context,
+            drops: Drops::new(context),
+            map: None,
+        }
+    }
+
+    /// `MapAnnotations`, or `None` outside annotated mode.
+    pub fn new_map(context: &'c Context) -> Option<Self> {
+        let ann = context.annotation.as_ref()?;
+
+        Some(Self {
+            map: Some((ann.clone(), format_ident!("New{}", ann))),
+            ..Self::new(context)
+        })
+    }
+
+    pub fn create_fold(&self) -> TokenStream {
+        let mut tokens = TokenStream::default();
+
+        let (func_impl, fold_impl) = self.func_impl();
+        let generics = self.trait_generics();
+        let (params, _, where_clause) = generics.split_for_impl();
+
+        let (ann, new_ann) = match &self.map {
+            Some(map) => map,
+            None => {
+                let arena = if self.context.arena {
+                    let arena_type = self.context.arena_type();
+
+                    quote! {
+                        /// The arena that the ids in the folded nodes point
+                        /// into. The node behind an id is replaced by its
+                        /// folded copy, once for every id it is reached
+                        /// through, and the id is kept.
+                        fn arena_mut(&mut self) -> &mut #arena_type;
+                    }
+                } else {
+                    TokenStream::new()
+                };
+
+                tokens.append_all(quote! {
+                    pub trait Fold #params #where_clause {
+                        #arena
+
+                        #func_impl
+                    }
+
+                    #fold_impl
+                });
+
+                return tokens;
+            }
+        };
+
+        let (_, args, _) = generics.split_for_impl();
+        let mut closure_generics = generics.clone();
+        closure_generics
+            .params
+            .push(parse_quote! { M: FnMut(#ann) -> #new_ann });
+        let (closure_params, _, _) = closure_generics.split_for_impl();
+
+        tokens.append_all(quote! {
+            /// Rebuilds a tree with every annotation mapped by `annotate`,
+            /// children before their parents.
+            pub trait MapAnnotations #params #where_clause {
+                fn annotate(&mut self, annotation: #ann) -> #new_ann;
+
+                #func_impl
+            }
+
+            #fold_impl
+
+            /// Maps every annotation with a closure.
+            pub struct AnnotationMap<M>(pub M);
+
+            impl #closure_params MapAnnotations #args for AnnotationMap<M> #where_clause {
+                fn annotate(&mut self, annotation: #ann) -> #new_ann {
+                    (self.0)(annotation)
+                }
+            }
+        });
+
+        let root_type = self.context.root_type();
+        for new_type in root_type.iter().chain(&self.context.new_types) {
+            tokens.append_all(self.map_annotations(new_type));
+        }
+
+        tokens
+    }
+
+    /// The parameters of the trait: those of the new types, and for
+    /// `MapAnnotations` the new annotation type.
+    fn trait_generics(&self) -> Generics {
+        let mut generics = self.context.cloning_arena(&self.context.generics);
+        if let Some((_, new_ann)) = &self.map {
+            generics.params.push(parse_quote! { #new_ann });
+        }
+
+        generics
+    }
+
+    /// `fold` or `map`, the start of every method's name.
+    fn prefix(&self) -> &'static str {
+        if self.map.is_some() {
+            "map"
+        } else {
+            "fold"
+        }
+    }
+
+    /// The type a value of type `ty` is folded into.
+    fn output<T: ToTokens>(&self, ty: T) -> TokenStream {
+        match &self.map {
+            Some((ann, new_ann)) => rename(ty.to_token_stream(), ann, new_ann),
+            None => ty.to_token_stream(),
+        }
+    }
+
+    /// An expression giving the annotation `expr` its folded value.
+    fn annotate(&self, expr: TokenStream) -> TokenStream {
+        if self.map.is_some() {
+            quote! { folder.annotate(#expr) }
+        } else {
+            expr
+        }
+    }
+
+    /// A type's `map_annotations`, when the caller can name every parameter
+    /// of `MapAnnotations` through it.
+    fn map_annotations(&self, new_type: &NewType) -> TokenStream {
+        let (ann, new_ann) = match &self.map {
+            Some(map) => map,
+            None => return TokenStream::new(),
+        };
+
+        let generics = new_type.generics();
+        let own: Vec<&Ident> = generics.type_params().map(|t| &t.ident).collect();
+        if !generics.params.iter().any(|p| param_ident(p) == ann)
+            || self
+                .context
+                .generics
+                .type_params()
+                .any(|t| !own.contains(&&t.ident))
+        {
+            return TokenStream::new();
+        }
+
+        let name = new_type.name();
+        let (params, args, where_clause) = generics.split_for_impl();
+        let output = self.output(self.context.ty(name));
+        let map = format_ident!("map_{}", name.to_string().to_lowercase());
+
+        quote! {
+            impl #params #name #args #where_clause {
+                /// This tree with every annotation mapped by `annotate`,
+                /// children before their parents.
+                pub fn map_annotations<#new_ann>(self, annotate: impl FnMut(#ann) -> #new_ann) -> #output {
+                    #map(&mut AnnotationMap(annotate), self)
+                }
+            }
+        }
+    }
+
+    /// Returns the trait methods and the `fold_*` functions they delegate to.
+    pub fn func_impl(&self) -> (TokenStream, TokenStream) {
+        let mut funcs = TokenStream::default();
+        let mut folds = TokenStream::default();
+
+        let root_type = self.context.root_type();
+
+        for new_type in root_type.iter().chain(&self.context.new_types) {
+            let (func, fold) = self.single_func(new_type);
+            funcs.extend(func);
+            folds.extend(fold);
+        }
+
+        (funcs, folds)
+    }
+
+    /// `fold_{name}`, or `None` if `ty` is not one of the new types.
+    fn fold_ident(&self, ty: &Type) -> Option<Ident> {
+        match Shape::of(ty, &self.new_idents) {
+            Shape::Node(node) => Some(format_ident!(
+                "{}_{}",
+                self.prefix(),
+                node.to_string().to_lowercase()
+            )),
+            _ => None,
+        }
+    }
+
+    fn shape(&self, ty: &Type) -> Shape {
+        Shape::resolving(ty, &self.new_idents, &self.context.arena_ids())
+    }
+
+    /// An expression folding every node reachable through the owned `expr`.
+    fn walk(&self, ty: &Type, expr: TokenStream) -> TokenStream {
+        if self.map.iter().any(|(ann, _)| is_ident(ty, ann)) {
+            return self.annotate(expr);
+        }
+
+        self.shape(ty)
+            .fold(expr, &quote! { folder.arena_mut() }, &|node, expr| {
+                let fold = format_ident!("{}_{}", self.prefix(), node.to_string().to_lowercase());
+                quote! { folder.#fold(#expr) }
+            })
+    }
+
+    /// A trait method `fold_{name}` whose default body is the free function
+    /// of the same name, along with that function itself. They take each of
+    /// `args`, a name and a type, and return `output`.
+    fn delegate(
+        &self,
+        name: &str,
+        span: Span,
+        args: &[(Ident, TokenStream)],
+        output: TokenStream,
+        body: TokenStream,
+    ) -> (TokenStream, TokenStream) {
+        let fold_name = format_ident!("{}_{}", self.prefix(), name.to_lowercase(), span = span);
+        let arg: Vec<&Ident> = args.iter().map(|(arg, _)| arg).collect();
+        let ty: Vec<&TokenStream> = args.iter().map(|(_, ty)| ty).collect();
+
+        let mut generics = self.trait_generics();
+        let (_, fold_args, _) = generics.split_for_impl();
+        let folder: GenericParam = if self.map.is_some() {
+            parse_quote! { F: MapAnnotations #fold_args }
+        } else {
+            parse_quote! { F: Fold #fold_args }
+        };
+        generics.params.push(folder);
+        let (params, _, where_clause) = generics.split_for_impl();
+
+        let func = quote! {
+            fn #fold_name(&mut self, #(#arg: #ty),*) -> #output where Self: Sized {
+                #fold_name(self, #(#arg),*)
+            }
+        };
+
+        let fold = quote! {
+            #[allow(clippy::boxed_local)]
+            pub fn #fold_name #params(folder: &mut F, #(#arg: #ty),*) -> #output #where_clause {
+                #body
+            }
+        };
+
+        (func, fold)
+    }
+
+    pub fn single_func(&self, new_type: &NewType) -> (TokenStream, TokenStream) {
+        let mut tokens = TokenStream::default();
+        let mut folds = TokenStream::default();
+
+        match new_type {
+            NewType::Enum(e) => {
+                let name = &e.name;
+                let name_lower = lowercase(name);
+                let ty = self.context.ty(name);
+
+                // Raw variants are rebuilt as they are
+                let raw_variants = e.variants.iter().filter(|v| v.is_unit());
+
+                // New Type variants will be: fold_newtype(newtype)
+                // Basic Type variants will be: fold_name_variantname(basic_type);
+                let (new_type_variants, basic_type_variants): (Vec<_>, Vec<_>) = e
+                    .variants
+                    .iter()
+                    .filter(|v| v.ty.is_some())
+                    .partition(|v| v.ty.as_ref().and_then(|t| self.fold_ident(t)).is_some());
+
+                let raw_idents = raw_variants.clone().map(|v| &v.name);
+                let raw_cfgs = raw_variants.clone().map(|v| v.cfg());
+                let raw_constructors = raw_idents.clone().map(lowercase);
+                let new_type_idents = new_type_variants.iter().map(|v| &v.name);
+                let new_type_cfgs = new_type_variants.iter().map(|v| v.cfg());
+                let new_type_constructors = new_type_idents.clone().map(lowercase);
+                let new_type_fold = new_type_variants
+                    .iter()
+                    .flat_map(|v| v.ty.as_ref().and_then(|t| self.fold_ident(t)));
+                let basic_idents = basic_type_variants.iter().map(|v| &v.name);
+                let basic_cfgs = basic_type_variants.iter().map(|v| v.cfg());
+                let basic_constructors = basic_idents.clone().map(lowercase);
+                let basic_fold = basic_idents.clone().map(|i| {
+                    format_ident!(
+                        "{}_{}_{}",
+                        self.prefix(),
+                        name_lower,
+                        i.to_string().to_lowercase(),
+                        span = i.span()
+                    )
+                });
+
+                // A type with a `Drop` impl can't be moved out of, so each
+                // variant is read out of it instead, and it is never dropped.
+                // The fields can't be swapped for placeholders, since a leaf
+                // need not have a value to leave behind
+                let has_drop = self.drops.has_drop(name);
+                let read = |binding: &Ident| {
+                    if has_drop {
+                        // SAFETY: the enum is in a `ManuallyDrop` that is never
+                        // dropped or used again, and each binding of the one
+                        // matching variant is read exactly once, so every field
+                        // is moved out once and dropped once
+                        quote! { unsafe { std::ptr::read(#binding) } }
+                    } else {
+                        quote! { #binding }
+                    }
+                };
+                let scrutinee = if has_drop {
+                    quote! {
+                        let #name_lower = std::mem::ManuallyDrop::new(#name_lower);
+                        match &*#name_lower
+                    }
+                } else {
+                    quote! { match #name_lower }
+                };
+                let v = read(&Ident::new("v", Span::call_site()));
+
+                // Variants with several or named fields will be:
+                // fold_name_variantname(field, ..), rebuilding the variant
+                let compound_variants: Vec<_> =
+                    e.variants.iter().filter(|v| v.fields.is_some()).collect();
+                let compound_cfgs = compound_variants.iter().map(|v| v.cfg());
+                let compound_patterns = compound_variants.iter().map(|v| v.pattern(name));
+                let compound_calls = compound_variants.iter().map(|v| {
+                    let fold = format_ident!(
+                        "{}_{}_{}",
+                        self.prefix(),
+                        name_lower,
+                        v.name.to_string().to_lowercase(),
+                        span = v.name.span()
+                    );
+                    let reads = v.bindings().into_iter().map(|(binding, _)| read(&binding));
+
+                    quote! { folder.#fold(#(#reads),*) }
+                });
+
+                let (func, fold) = self.delegate(
+                    &name.to_string(),
+                    name.span(),
+                    &[(name_lower.clone(), ty.clone())],
+                    self.output(&ty),
+                    quote! {
+                        #scrutinee {
+                            #(
+                                #new_type_cfgs
+                                #name::#new_type_idents(v) => #name::#new_type_constructors(folder.#new_type_fold(#v)),
+                            )*
+                            #(
+                                #basic_cfgs
+                                #name::#basic_idents(v) => #name::#basic_constructors(folder.#basic_fold(#v)),
+                            )*
+                            #(
+                                #compound_cfgs
+                                #compound_patterns => #compound_calls,
+                            )*
+                            #(
+                                #raw_cfgs
+                                #name::#raw_idents => #name::#raw_constructors(),
+                            )*
+                        }
+                    },
+                );
+                tokens.append_all(func);
+                folds.append_all(fold);
+
+                for variant in basic_type_variants {
+                    let ident = &variant.name;
+                    let ty = variant.ty.as_ref().unwrap();
+                    let v = Ident::new("v", Span::call_site());
+
+                    if self.shape(ty).is_leaf() {
+                        let fold_name = format_ident!(
+                            "{}_{}_{}",
+                            self.prefix(),
+                            name_lower,
+                            ident.to_string().to_lowercase(),
+                            span = ident.span()
+                        );
+                        let output = self.output(ty);
+                        let body = if self.map.iter().any(|(ann, _)| is_ident(ty, ann)) {
+                            quote! { self.annotate(v) }
+                        } else {
+                            quote! { v }
+                        };
+
+                        tokens.append_all(quote! {
+                            fn #fold_name(&mut self, v: #ty) -> #output where Self: Sized {
+                                #body
+                            }
+                        });
+                    } else {
+                        let walk = self.walk(ty, quote! { v });
+                        let (func, fold) = self.delegate(
+                            &format!("{}_{}", name, ident),
+                            ident.span(),
+                            &[(v, ty.to_token_stream())],
+                            self.output(ty),
+                            walk,
+                        );
+                        tokens.append_all(func);
+                        folds.append_all(fold);
+                    }
+                }
+
+                for variant in compound_variants {
+                    let ident = &variant.name;
+                    let bindings = variant.bindings();
+                    let pattern = variant.pattern(name);
+
+                    // Fields without nodes are kept as they are
+                    let walks = bindings.iter().filter_map(|(binding, ty)| {
+                        let walk = self.walk(ty, quote! { #binding });
+                        if *binding == walk.to_string() {
+                            None
+                        } else {
+                            Some(quote! { let #binding = #walk; })
+                        }
+                    });
+                    let args: Vec<(Ident, TokenStream)> = bindings
+                        .iter()
+                        .map(|(binding, ty)| (binding.clone(), ty.to_token_stream()))
+                        .collect();
+
+                    let (func, fold) = self.delegate(
+                        &format!("{}_{}", name, ident),
+                        ident.span(),
+                        &args,
+                        self.output(self.context.ty(name)),
+                        quote! {
+                            #(#walks)*
+                            #pattern
+                        },
+                    );
+                    tokens.append_all(func);
+                    folds.append_all(fold);
+                }
+            }
+            NewType::Struct(s) => {
+                let name = &s.name;
+                let name_lower = lowercase(name);
+                let ty = self.context.ty(name);
+
+                let mut field_names: Vec<Ident> =
+                    s.fields.iter().map(|f| f.ident.clone()).collect();
+                let mut field_cfgs: Vec<TokenStream> = s.fields.iter().map(|f| f.cfg()).collect();
+                let mut folded_fields: Vec<TokenStream> = s
+                    .fields
+                    .iter()
+                    .map(|f| {
+                        let ident = &f.ident;
+                        self.walk(&f.ty, quote! { #ident })
+                    })
+                    .collect();
+
+                // The span is kept as it is, and the annotation folded last
+                if s.spanned {
+                    field_names.push(Ident::new("span", Span::call_site()));
+                    field_cfgs.push(TokenStream::new());
+                    folded_fields.push(quote! { span });
+                }
+                if s.annotation.is_some() {
+                    field_names.push(Ident::new("annotation", Span::call_site()));
+                    field_cfgs.push(TokenStream::new());
+                    folded_fields.push(self.annotate(quote! { annotation }));
+                }
+
+                // The fields are borrowed before they are read, since one may
+                // share the struct's name. As for enums, a struct with a `Drop`
+                // impl is read out of instead of moved out of.
+                //
+                // SAFETY: the struct is in a `ManuallyDrop` that is never
+                // dropped or used again, and each field is read exactly once,
+                // so every field is moved out once and dropped once
+                let destructure = if self.drops.has_drop(name) {
+                    quote! {
+                        let #name_lower = std::mem::ManuallyDrop::new(#name_lower);
+                        let #name { #(#field_cfgs #field_names),* } = &*#name_lower;
+                        #(
+                            #field_cfgs
+                            let #field_names = unsafe { std::ptr::read(#field_names) };
+                        )*
+                    }
+                } else {
+                    quote! {
+                        let #name { #(#field_cfgs #field_names),* } = #name_lower;
+                    }
+                };
+
+                let (func, fold) = self.delegate(
+                    &name.to_string(),
+                    name.span(),
+                    &[(name_lower.clone(), ty.clone())],
+                    self.output(&ty),
+                    quote! {
+                        #destructure
+
+                        #name { #(#field_cfgs #field_names: #folded_fields),* }
+                    },
+                );
+                tokens.append_all(func);
+                folds.append_all(fold);
+            }
+            NewType::WrapperStruct(s) => {
+                let name = &s.name;
+                let name_lower = lowercase(name);
+                let ty = self.context.ty(name);
+
+                let mut rest = Vec::new();
+                let mut folded_rest = Vec::new();
+                if s.spanned {
+                    rest.push(quote! { span });
+                    folded_rest.push(quote! { span });
+                }
+                if s.annotation.is_some() {
+                    rest.push(quote! { annotation });
+                    folded_rest.push(self.annotate(quote! { annotation }));
+                }
+
+                let body = if rest.is_empty() {
+                    let inner = self.walk(&s.ty, quote! { #name_lower.into_inner() });
+
+                    quote! {
+                        #name::new(#inner)
+                    }
+                } else {
+                    let inner = self.walk(&s.ty, quote! { inner });
+
+                    quote! {
+                        let #name(inner, #(#rest),*) = #name_lower;
+
+                        #name(#inner, #(#folded_rest),*)
+                    }
+                };
+
+                let (func, fold) = self.delegate(
+                    &name.to_string(),
+                    name.span(),
+                    &[(name_lower.clone(), ty.clone())],
+                    self.output(&ty),
+                    body,
+                );
+                tokens.append_all(func);
+                folds.append_all(fold);
+            }
+        }
+
+        (tokens, folds)
+    }
+}
+
+/// Whether `ty` is just the type parameter `ident`.
+fn is_ident(ty: &Type, ident: &Ident) -> bool {
+    match ty {
+        Type::Path(p) => p.qself.is_none() && p.path.is_ident(ident),
+        _ => false,
+    }
+}
+
+/// `tokens` with every `from` replaced by `to`.
+fn rename(tokens: TokenStream, from: &Ident, to: &Ident) -> TokenStream {
+    tokens
+        .into_iter()
+        .map(|tree| match tree {
+            TokenTree::Ident(ident) if ident == *from => TokenTree::Ident(to.clone()),
+            TokenTree::Group(group) => {
+                let mut renamed = Group::new(group.delimiter(), rename(group.stream(), from, to));
+                renamed.set_span(group.span());
+                TokenTree::Group(renamed)
+            }
+            tree => tree,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    #[test]
+    fn simple_fold() {
+        let context: Context = parse_quote! {
+            Lit |isize|,
+            Expr |Lit|,
+            Binop: struct Binop {
+                lhs: Expr,
+                rhs: Expr,
+            },
+            E: enum E {
+                Alpha(isize), // Basic type
+                Beta(Expr),   // New Type
+                Gamma,        // Raw Type
+            }
+        };
+
+        let fold = Fold::new(&context);
+        let impl_tokens = fold.create_fold();
+
+        println!("{}", impl_tokens);
+    }
+}
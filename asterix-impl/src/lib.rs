@@ -1,21 +1,86 @@
 extern crate proc_macro;
 
 use proc_macro::TokenStream;
+use quote::quote;
 use syn::parse_macro_input;
 
+mod arena;
 pub(crate) mod context;
+mod drop;
+mod fold;
+mod module;
+mod node;
+mod shape;
+mod span;
+mod validate;
 mod visitor;
 
+use arena::Arena;
 use context::Context;
+use drop::Drops;
+use fold::Fold;
+use module::AstModule;
+use node::Nodes;
+use span::Spans;
 use visitor::Visitor;
 
 #[proc_macro]
 pub fn ast(input: TokenStream) -> TokenStream {
     let context = parse_macro_input!(input as Context);
-    let visitor = Visitor::new(&context);
-
-    let visit_impl = visitor.create_visitor();
+    let visit_impl = create_impls(&context);
     let ast_impl = context.create_ast(Some(visit_impl));
 
     ast_impl.into()
 }
+
+/// `ast!` over the structs and enums of the module it is applied to, which
+/// keeps its other items and those marked `#[asterix(skip)]`. The module's
+/// inner attributes are the header.
+#[proc_macro_attribute]
+pub fn ast_module(args: TokenStream, input: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        let args = proc_macro2::TokenStream::from(args);
+        return syn::Error::new_spanned(
+            args,
+            "ast_module takes its options as inner attributes of the module",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    let module = parse_macro_input!(input as AstModule);
+    let visit_impl = create_impls(&module.context);
+
+    module.create_module(visit_impl).into()
+}
+
+/// The visitors, folds and other items generated for the types of `context`.
+fn create_impls(context: &Context) -> proc_macro2::TokenStream {
+    let visitor = Visitor::new(context).create_visitor();
+    let visitor_mut = Visitor::new_mut(context).create_visitor();
+    let visitor_exhaustive = Visitor::new_exhaustive(context).create_visitor();
+    let visitor_try = Visitor::new_try(context).create_visitor();
+    let visitor_ctx = Visitor::new_ctx(context).create_visitor();
+    let visitor_typed = Visitor::new_typed(context).create_visitor();
+    let fold = Fold::new(context).create_fold();
+    let map_annotations = Fold::new_map(context).map(|map| map.create_fold());
+    let nodes = Nodes::new(context).create_nodes();
+    let drops = Drops::new(context).create_drops();
+    let arena = Arena::new(context).create_arena();
+    let spans = Spans::new(context).create_spans();
+
+    quote! {
+        #visitor
+        #visitor_mut
+        #visitor_exhaustive
+        #visitor_try
+        #visitor_ctx
+        #visitor_typed
+        #fold
+        #map_annotations
+        #nodes
+        #drops
+        #arena
+        #spans
+    }
+}
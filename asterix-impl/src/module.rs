@@ -0,0 +1,433 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{
+    parse::{Parse, ParseStream, Parser},
+    AttrStyle, Attribute, Fields, Ident, Item, ItemEnum, ItemMod, ItemStruct, Result, UseTree,
+    Visibility,
+};
+
+use crate::context::Context;
+
+/// The attribute form of `ast!`: a hand-written module whose structs and
+/// enums are the grammar.
+///
+/// Each struct with named fields, tuple struct with one field, and enum is
+/// read as the grammar's declaration of it, and the module's inner
+/// attributes as its header. Every other item, and each struct or enum
+/// marked `#[asterix(skip)]`, stays as it is written, and the types it
+/// brings into the module, like those of a `use`, can be named by the
+/// grammar.
+pub struct AstModule {
+    pub attrs: Vec<Attribute>,
+    pub vis: Visibility,
+    pub ident: Ident,
+    /// The items that are not part of the grammar.
+    pub items: Vec<Item>,
+    pub context: Context,
+}
+
+impl AstModule {
+    pub fn create_module(self, visitor: TokenStream) -> TokenStream {
+        let Self {
+            attrs,
+            vis,
+            ident,
+            items,
+            context,
+        } = self;
+        let generated = context.create_items(Some(visitor));
+
+        quote! {
+            #(#attrs)*
+            #vis mod #ident {
+                #(#items)*
+                #generated
+            }
+        }
+    }
+}
+
+impl Parse for AstModule {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let module: ItemMod = input.parse()?;
+        let content = match module.content {
+            Some((_, content)) => content,
+            None => {
+                return Err(syn::Error::new_spanned(
+                    module.ident,
+                    "expected a module with a body, `mod ast { .. }`",
+                ))
+            }
+        };
+
+        let (header, attrs): (Vec<Attribute>, Vec<Attribute>) = module
+            .attrs
+            .into_iter()
+            .partition(|attr| matches!(attr.style, AttrStyle::Inner(_)));
+        if let Some(attr) = header.iter().find(|attr| attr.path.is_ident("module")) {
+            return Err(syn::Error::new_spanned(
+                attr,
+                "the module of the grammar is the one `ast_module` is applied to",
+            ));
+        }
+
+        let mut entries = Vec::new();
+        let mut items = Vec::new();
+        let mut in_scope = Some(Vec::new());
+        for mut item in content {
+            let in_grammar = match &mut item {
+                Item::Struct(s) => !skip(&mut s.attrs)?,
+                Item::Enum(e) => !skip(&mut e.attrs)?,
+                _ => false,
+            };
+
+            match item {
+                Item::Struct(s) if in_grammar => entries.push(struct_entry(s)?),
+                Item::Enum(e) if in_grammar => entries.push(enum_entry(e)?),
+                item => {
+                    bring_into_scope(&item, &mut in_scope);
+                    items.push(item);
+                }
+            }
+        }
+
+        // Without a root, unless the header asks for one, and in a module of
+        // the name written
+        let ident = module.ident;
+        let grammar = quote! {
+            #![root = _]
+            #(#header)*
+            #![module = #ident]
+            #(#entries),*
+        };
+        let context = (|input: ParseStream| Context::parse_in_scope(input, in_scope.as_deref()))
+            .parse2(grammar)?;
+
+        Ok(AstModule {
+            attrs,
+            vis: module.vis,
+            ident,
+            items,
+            context,
+        })
+    }
+}
+
+/// Removes a `#[asterix(skip)]` from `attrs`, returning whether there was
+/// one.
+fn skip(attrs: &mut Vec<Attribute>) -> Result<bool> {
+    let position = match attrs.iter().position(|attr| attr.path.is_ident("asterix")) {
+        Some(position) => position,
+        None => return Ok(false),
+    };
+
+    let attr = attrs.remove(position);
+    match attr.parse_args::<Ident>() {
+        Ok(arg) if arg == "skip" => Ok(true),
+        _ => Err(syn::Error::new_spanned(
+            attr,
+            "expected `#[asterix(skip)]`, which keeps an item out of the grammar",
+        )),
+    }
+}
+
+/// The visibility of an item as the grammar declares it. The grammar reads
+/// no visibility as `pub`, so a private item is `pub(self)`.
+fn item_vis(vis: &Visibility) -> TokenStream {
+    match vis {
+        Visibility::Inherited => quote! { pub(self) },
+        vis => quote! { #vis },
+    }
+}
+
+/// `Name: struct Name { .. }` for a struct with named fields, or
+/// `Name: Name |T|` for a tuple struct with one field.
+fn struct_entry(s: ItemStruct) -> Result<TokenStream> {
+    let ItemStruct {
+        attrs,
+        vis,
+        ident,
+        generics,
+        fields,
+        ..
+    } = s;
+    let vis = item_vis(&vis);
+    let where_clause = &generics.where_clause;
+
+    match fields {
+        Fields::Named(named) => {
+            let fields = named.named.iter().map(|f| {
+                let (attrs, vis, ident, ty) = (&f.attrs, &f.vis, &f.ident, &f.ty);
+                quote! { #(#attrs)* #vis #ident: #ty }
+            });
+
+            Ok(quote! {
+                #ident: #(#attrs)* #vis struct #ident #generics #where_clause {
+                    #(#fields),*
+                }
+            })
+        }
+        Fields::Unnamed(unnamed)
+            if unnamed.unnamed.len() == 1 && unnamed.unnamed[0].attrs.is_empty() =>
+        {
+            let (inner_vis, ty) = (&unnamed.unnamed[0].vis, &unnamed.unnamed[0].ty);
+
+            Ok(quote! {
+                #ident: #(#attrs)* #vis #ident #generics #where_clause |#inner_vis #ty|
+            })
+        }
+        _ => Err(syn::Error::new_spanned(
+            ident,
+            "a struct of the grammar has named fields, or one unnamed field without attributes",
+        )),
+    }
+}
+
+/// `Name: enum Name { .. }`, with each variant as it is written.
+fn enum_entry(e: ItemEnum) -> Result<TokenStream> {
+    let ItemEnum {
+        attrs,
+        vis,
+        ident,
+        generics,
+        variants,
+        ..
+    } = e;
+    let vis = item_vis(&vis);
+    let where_clause = &generics.where_clause;
+
+    let variants = variants
+        .iter()
+        .map(|v| {
+            if let Some((_, discriminant)) = &v.discriminant {
+                return Err(syn::Error::new_spanned(
+                    discriminant,
+                    "a variant of the grammar has no discriminant",
+                ));
+            }
+
+            let (attrs, name) = (&v.attrs, &v.ident);
+            let fields = match &v.fields {
+                Fields::Named(named) => {
+                    let fields = named.named.iter().map(|f| {
+                        let (attrs, ident, ty) = (&f.attrs, &f.ident, &f.ty);
+                        quote! { #(#attrs)* #ident: #ty }
+                    });
+                    quote! { { #(#fields),* } }
+                }
+                Fields::Unnamed(unnamed) => {
+                    if let Some(field) = unnamed.unnamed.iter().find(|f| !f.attrs.is_empty()) {
+                        return Err(syn::Error::new_spanned(
+                            &field.attrs[0],
+                            "an unnamed field of the grammar has no attributes",
+                        ));
+                    }
+
+                    let types = unnamed.unnamed.iter().map(|f| &f.ty);
+                    quote! { (#(#types),*) }
+                }
+                Fields::Unit => TokenStream::new(),
+            };
+
+            Ok(quote! { #(#attrs)* #name #fields })
+        })
+        .collect::<Result<Vec<TokenStream>>>()?;
+
+    Ok(quote! {
+        #ident: #(#attrs)* #vis enum #ident #generics #where_clause {
+            #(#variants),*
+        }
+    })
+}
+
+/// Adds the types that `item` declares or imports to `in_scope`, which
+/// becomes `None` once an item may bring in any type.
+fn bring_into_scope(item: &Item, in_scope: &mut Option<Vec<Ident>>) {
+    let names = match in_scope {
+        Some(names) => names,
+        None => return,
+    };
+
+    match item {
+        Item::Use(u) if !used(&u.tree, names) => *in_scope = None,
+        Item::Struct(s) => names.push(s.ident.clone()),
+        Item::Enum(e) => names.push(e.ident.clone()),
+        Item::Type(t) => names.push(t.ident.clone()),
+        Item::Union(u) => names.push(u.ident.clone()),
+        Item::Trait(t) => names.push(t.ident.clone()),
+        Item::Macro(_) | Item::Verbatim(_) => *in_scope = None,
+        _ => (),
+    }
+}
+
+/// Adds the names that `tree` imports to `names`, or returns `false` for a
+/// glob import.
+fn used(tree: &UseTree, names: &mut Vec<Ident>) -> bool {
+    match tree {
+        UseTree::Path(path) => used(&path.tree, names),
+        UseTree::Name(name) => {
+            if name.ident != "self" {
+                names.push(name.ident.clone());
+            }
+            true
+        }
+        UseTree::Rename(rename) => {
+            names.push(rename.rename.clone());
+            true
+        }
+        UseTree::Glob(_) => false,
+        UseTree::Group(group) => group.items.iter().all(|tree| used(tree, names)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::NewType;
+    use quote::ToTokens;
+
+    #[test]
+    fn parse_module() {
+        let module: AstModule = syn::parse_quote! {
+            /// The surface syntax.
+            pub mod surface {
+                #![visitor = SurfaceVisitor]
+
+                use std::collections::HashMap;
+
+                #[derive(Debug, Clone, PartialEq)]
+                pub enum Expr {
+                    Call(Name, Vec<Expr>),
+                    Let { name: Name, value: Box<Expr> },
+                    Scope(Env),
+                    Unit,
+                }
+
+                pub(crate) struct Name(pub String);
+
+                struct Call {
+                    pub callee: Name,
+                    args: Vec<Expr>,
+                }
+
+                #[asterix(skip)]
+                #[derive(Debug, Clone, PartialEq)]
+                pub struct Env(HashMap<String, Expr>);
+
+                impl Name {
+                    pub fn len(&self) -> usize {
+                        self.0.len()
+                    }
+                }
+            }
+        };
+
+        assert_eq!(module.attrs.len(), 1);
+        assert_eq!(module.items.len(), 3);
+        assert_eq!(module.context.module, "surface");
+        assert_eq!(module.context.visitor, "SurfaceVisitor");
+        assert!(module.context.root.is_none());
+
+        let types: Vec<(String, String)> = module
+            .context
+            .new_types
+            .iter()
+            .map(|nt| {
+                let vis = match nt {
+                    NewType::Enum(e) => &e.vis,
+                    NewType::Struct(s) => &s.vis,
+                    NewType::WrapperStruct(w) => &w.vis,
+                };
+                (nt.name().to_string(), vis.to_token_stream().to_string())
+            })
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                ("Expr".to_string(), "pub".to_string()),
+                ("Name".to_string(), "pub ( crate )".to_string()),
+                ("Call".to_string(), "pub ( self )".to_string()),
+            ]
+        );
+
+        // The derives every type has are left out
+        match &module.context.new_types[0] {
+            NewType::Enum(e) => {
+                let attrs: Vec<String> = e
+                    .attrs
+                    .iter()
+                    .map(|attr| attr.to_token_stream().to_string())
+                    .collect();
+                assert_eq!(attrs, vec!["# [ derive ( PartialEq ) ]"]);
+            }
+            nt => panic!("expected an enum, got {:?}", nt),
+        }
+
+        match &module.context.new_types[2] {
+            NewType::Struct(s) => {
+                let fields: Vec<String> = s
+                    .fields
+                    .iter()
+                    .map(|f| f.vis.to_token_stream().to_string())
+                    .collect();
+                assert_eq!(fields, vec!["pub", ""]);
+            }
+            nt => panic!("expected a struct, got {:?}", nt),
+        }
+
+        println!("{}", module.create_module(TokenStream::new()));
+    }
+
+    #[test]
+    fn reject_module() {
+        let undefined = syn::parse2::<AstModule>(quote! {
+            mod ast {
+                enum Expr {
+                    Scope(HashMap<String, Expr>),
+                }
+            }
+        });
+        assert!(undefined.is_err());
+
+        let glob = syn::parse2::<AstModule>(quote! {
+            mod ast {
+                use std::collections::*;
+
+                enum Expr {
+                    Scope(HashMap<String, Expr>),
+                }
+            }
+        });
+        assert!(glob.is_ok());
+
+        let unit = syn::parse2::<AstModule>(quote! {
+            mod ast {
+                struct Unit;
+            }
+        });
+        assert!(unit.is_err());
+
+        let skipped = syn::parse2::<AstModule>(quote! {
+            mod ast {
+                #[asterix(skip)]
+                struct Unit;
+
+                enum Expr {
+                    Unit(Unit),
+                }
+            }
+        });
+        assert!(skipped.is_ok());
+
+        let module = syn::parse2::<AstModule>(quote! {
+            mod ast {
+                #![module = surface]
+
+                enum Expr {
+                    Lit(isize),
+                }
+            }
+        });
+        assert!(module.is_err());
+    }
+}
@@ -0,0 +1,450 @@
+use std::collections::HashSet;
+
+use proc_macro2::{Span, TokenStream};
+use quote::{quote, TokenStreamExt};
+use syn::{parse_quote, Generics, Lifetime, Type};
+
+use crate::context::{outliving, param_ident, Context, NewType};
+use crate::shape::Shape;
+
+pub struct Nodes<'c> {
+    pub new_idents: HashSet<String>,
+    context: &'c Context,
+}
+
+impl<'c> Nodes<'c> {
+    pub fn new(context: &'c Context) -> Self {
+        let new_idents: HashSet<String> = context
+            .new_types
+            .iter()
+            .map(|n| n.name().to_string())
+            .collect();
+
+        Self {
+            new_idents,
+            context,
+        }
+    }
+
+    pub fn create_nodes(&self) -> TokenStream {
+        let mut tokens = TokenStream::default();
+
+        let names = self.context.node_names();
+        let types: Vec<TokenStream> = names.iter().map(|n| self.context.ty(n)).collect();
+        let names_from = names.iter();
+        let names_children = names.iter();
+        let names_node = names.iter();
+        let (types_from, types_node) = (types.iter(), types.iter());
+
+        // `'a` is the lifetime of the references, and `'n` that of the
+        // methods' `self`
+        let generics = &self.context.generics;
+        let (params, args, where_clause) = generics.split_for_impl();
+        let a_generics = outliving(generics, "'a");
+        let (a_params, a_args, a_where) = a_generics.split_for_impl();
+        let n_generics = outliving(generics, "'n");
+        let (_, n_args, _) = n_generics.split_for_impl();
+        let n_where = &n_generics.where_clause;
+
+        // `Descendants` takes the type `T` it finds after the parameters
+        let mut d_generics = a_generics.clone();
+        d_generics.params.push(parse_quote! { T });
+        let (d_params, d_args, _) = d_generics.split_for_impl();
+        let mut d_impl_generics = a_generics.clone();
+        d_impl_generics
+            .params
+            .push(parse_quote! { T: AstNode #args + 'a });
+        let (d_impl_params, _, _) = d_impl_generics.split_for_impl();
+        let mut d_n_generics = n_generics.clone();
+        d_n_generics.params.push(parse_quote! { T });
+        let (_, d_n_args, _) = d_n_generics.split_for_impl();
+
+        // In arena mode, every method that follows ids takes the arena
+        let arena_type = self.context.arena_type();
+        let arena_param = self.arena_param("'a");
+        let n_arena_param = self.arena_param("'n");
+        let arena = self.arena_arg(quote! { arena });
+        let arena_field = self.arena_arg(quote! { arena: &'a #arena_type, });
+        let arena_init = self.arena_arg(quote! { arena, });
+        let own_arena = self.arena_arg(quote! { self.arena });
+
+        let root_type = self.context.root_type();
+        let children = root_type
+            .iter()
+            .chain(&self.context.new_types)
+            .map(|nt| self.children(nt));
+
+        tokens.append_all(quote! {
+            #[derive(Debug)]
+            pub enum NodeRef #a_params #a_where {
+                #(#names(&'a #types)),*
+            }
+
+            impl #a_params Clone for NodeRef #a_args #a_where {
+                fn clone(&self) -> Self {
+                    *self
+                }
+            }
+
+            impl #a_params Copy for NodeRef #a_args #a_where {}
+
+            impl #a_params NodeRef #a_args #a_where {
+                pub fn children(self #arena_param) -> impl Iterator<Item = NodeRef #a_args> {
+                    let children: Vec<NodeRef #a_args> = match self {
+                        #(
+                            NodeRef::#names_children(node) => node.children(#arena).collect(),
+                        )*
+                    };
+
+                    children.into_iter()
+                }
+
+                pub fn iter_preorder(self #arena_param) -> Preorder #a_args {
+                    Preorder {
+                        #arena_init
+                        stack: vec![(self, 0)],
+                    }
+                }
+
+                pub fn iter_postorder(self #arena_param) -> Postorder #a_args {
+                    Postorder {
+                        #arena_init
+                        stack: vec![(self, 0, false)],
+                    }
+                }
+
+                pub fn iter_bfs(self #arena_param) -> BreadthFirst #a_args {
+                    BreadthFirst {
+                        #arena_init
+                        queue: vec![(self, 0)].into_iter().collect(),
+                    }
+                }
+            }
+
+            /// Yields every node with its depth, parents before children.
+            #[derive(Debug, Clone)]
+            pub struct Preorder #a_params #a_where {
+                #arena_field
+                stack: Vec<(NodeRef #a_args, usize)>,
+            }
+
+            impl #a_params Iterator for Preorder #a_args #a_where {
+                type Item = (NodeRef #a_args, usize);
+
+                fn next(&mut self) -> Option<Self::Item> {
+                    let (node, depth) = self.stack.pop()?;
+
+                    let children: Vec<NodeRef #a_args> = node.children(#own_arena).collect();
+                    self.stack
+                        .extend(children.into_iter().rev().map(|child| (child, depth + 1)));
+
+                    Some((node, depth))
+                }
+            }
+
+            /// Yields every node with its depth, children before parents.
+            #[derive(Debug, Clone)]
+            pub struct Postorder #a_params #a_where {
+                #arena_field
+                stack: Vec<(NodeRef #a_args, usize, bool)>,
+            }
+
+            impl #a_params Iterator for Postorder #a_args #a_where {
+                type Item = (NodeRef #a_args, usize);
+
+                fn next(&mut self) -> Option<Self::Item> {
+                    loop {
+                        let (node, depth, expanded) = self.stack.pop()?;
+
+                        if expanded {
+                            return Some((node, depth));
+                        }
+
+                        let children: Vec<NodeRef #a_args> = node.children(#own_arena).collect();
+                        self.stack.push((node, depth, true));
+                        self.stack.extend(
+                            children.into_iter().rev().map(|child| (child, depth + 1, false)),
+                        );
+                    }
+                }
+            }
+
+            /// Yields every node with its depth, one level at a time.
+            #[derive(Debug, Clone)]
+            pub struct BreadthFirst #a_params #a_where {
+                #arena_field
+                queue: std::collections::VecDeque<(NodeRef #a_args, usize)>,
+            }
+
+            impl #a_params Iterator for BreadthFirst #a_args #a_where {
+                type Item = (NodeRef #a_args, usize);
+
+                fn next(&mut self) -> Option<Self::Item> {
+                    let (node, depth) = self.queue.pop_front()?;
+
+                    self.queue
+                        .extend(node.children(#own_arena).map(|child| (child, depth + 1)));
+
+                    Some((node, depth))
+                }
+            }
+
+            /// Implemented by every node type, allowing typed searches through
+            /// the nodes below it.
+            pub trait AstNode #params: Sized #where_clause {
+                fn node_ref<'n>(&'n self) -> NodeRef #n_args #n_where;
+
+                fn from_node_ref<'n>(node: NodeRef #n_args) -> Option<&'n Self> #n_where;
+
+                /// Every node of type `T` strictly below `self`, in pre-order.
+                fn descendants<'n, T: AstNode #args + 'n>(&'n self #n_arena_param) -> Descendants #d_n_args #n_where {
+                    let mut nodes = self.node_ref().iter_preorder(#arena);
+                    nodes.next();
+
+                    Descendants {
+                        nodes,
+                        marker: std::marker::PhantomData,
+                    }
+                }
+
+                fn find_all<'n, T: AstNode #args + 'n>(&'n self #n_arena_param) -> Vec<&'n T> #n_where {
+                    self.descendants(#arena).collect()
+                }
+
+                fn first<'n, T: AstNode #args + 'n>(&'n self #n_arena_param) -> Option<&'n T> #n_where {
+                    self.descendants(#arena).next()
+                }
+            }
+
+            #[derive(Debug, Clone)]
+            pub struct Descendants #d_params #a_where {
+                nodes: Preorder #a_args,
+                marker: std::marker::PhantomData<fn() -> T>,
+            }
+
+            impl #d_impl_params Iterator for Descendants #d_args #a_where {
+                type Item = &'a T;
+
+                fn next(&mut self) -> Option<Self::Item> {
+                    self.nodes
+                        .by_ref()
+                        .find_map(|(node, _)| T::from_node_ref(node))
+                }
+            }
+
+            #(
+                impl #params AstNode #args for #types_node #where_clause {
+                    fn node_ref<'n>(&'n self) -> NodeRef #n_args #n_where {
+                        NodeRef::from(self)
+                    }
+
+                    #[allow(unreachable_patterns)]
+                    fn from_node_ref<'n>(node: NodeRef #n_args) -> Option<&'n Self> #n_where {
+                        match node {
+                            NodeRef::#names_node(node) => Some(node),
+                            _ => None,
+                        }
+                    }
+                }
+            )*
+
+            #(
+                impl #a_params From<&'a #types_from> for NodeRef #a_args #a_where {
+                    fn from(node: &'a #types_from) -> Self {
+                        NodeRef::#names_from(node)
+                    }
+                }
+            )*
+
+            #(#children)*
+        });
+
+        tokens
+    }
+
+    /// The parameters of a method on a type with parameters `own`, returning
+    /// nodes borrowed for `'n`: those of the other types, which the caller
+    /// picks, behind `'n`.
+    fn method_generics(&self, own: &Generics) -> Generics {
+        let mut others = self.context.generics.clone();
+        others.params = others
+            .params
+            .into_iter()
+            .filter(|param| {
+                !own.params
+                    .iter()
+                    .any(|p| param_ident(p) == param_ident(param))
+            })
+            .collect();
+
+        let mut generics = outliving(&others, "'n");
+        let n_generics = outliving(&self.context.generics, "'n");
+        generics.where_clause = n_generics.where_clause;
+
+        generics
+    }
+
+    /// `, arena: &'lifetime AstArena<..>` in arena mode, the parameter of
+    /// the methods that follow ids.
+    fn arena_param(&self, lifetime: &str) -> TokenStream {
+        let lifetime = Lifetime::new(lifetime, Span::call_site());
+        let arena_type = self.context.arena_type();
+
+        self.arena_arg(quote! { , arena: &#lifetime #arena_type })
+    }
+
+    /// `tokens` in arena mode, and nothing otherwise.
+    fn arena_arg(&self, tokens: TokenStream) -> TokenStream {
+        if self.context.arena {
+            tokens
+        } else {
+            TokenStream::new()
+        }
+    }
+
+    /// Statements pushing every node reachable through the reference `expr`
+    /// onto `children`.
+    fn push(&self, ty: &Type, expr: TokenStream) -> TokenStream {
+        let shape = Shape::resolving(ty, &self.new_idents, &self.context.arena_ids());
+        shape.visit(expr, false, &quote! { arena }, &|_, expr| {
+            quote! {
+                children.push(NodeRef::from(#expr));
+            }
+        })
+    }
+
+    /// The `children` method of a new type, yielding its direct child nodes.
+    fn children(&self, new_type: &NewType) -> TokenStream {
+        let pushes = match new_type {
+            NewType::Enum(e) => {
+                let name = &e.name;
+                let mut any = false;
+                let arms: Vec<TokenStream> = e
+                    .variants
+                    .iter()
+                    .map(|v| {
+                        let variant = &v.name;
+                        let cfg = v.cfg();
+
+                        if v.fields.is_some() {
+                            return match v.fields_arm(name, |ty, expr| self.push(ty, expr)) {
+                                Some(arm) => {
+                                    any = true;
+                                    quote! { #cfg #arm }
+                                }
+                                None => quote! { #cfg #name::#variant { .. } => {} },
+                            };
+                        }
+
+                        match &v.ty {
+                            Some(ty) => {
+                                let push = self.push(ty, quote! { v });
+                                if push.is_empty() {
+                                    quote! { #cfg #name::#variant(_) => {} }
+                                } else {
+                                    any = true;
+                                    quote! { #cfg #name::#variant(v) => { #push } }
+                                }
+                            }
+                            None => quote! { #cfg #name::#variant => {} },
+                        }
+                    })
+                    .collect();
+
+                if any {
+                    quote! {
+                        match self {
+                            #(#arms)*
+                        }
+                    }
+                } else {
+                    TokenStream::new()
+                }
+            }
+            NewType::Struct(s) => {
+                let pushes = s.fields.iter().map(|f| {
+                    let ident = &f.ident;
+                    let cfg = f.cfg();
+                    let push = self.push(&f.ty, quote! { &self.#ident });
+
+                    if cfg.is_empty() || push.is_empty() {
+                        push
+                    } else {
+                        quote! { #cfg { #push } }
+                    }
+                });
+
+                quote! { #(#pushes)* }
+            }
+            NewType::WrapperStruct(w) => self.push(&w.ty, quote! { &self.0 }),
+        };
+
+        let name = new_type.name();
+        let (params, args, where_clause) = new_type.generics().split_for_impl();
+        let method_generics = self.method_generics(new_type.generics());
+        let (method_params, _, method_where) = method_generics.split_for_impl();
+        let n_generics = outliving(&self.context.generics, "'n");
+        let (_, n_args, _) = n_generics.split_for_impl();
+        let arena_param = self.arena_param("'n");
+        let arena = self.arena_arg(quote! { arena });
+        // Not every type holds ids
+        let allow = self.arena_arg(quote! { #[allow(unused_variables)] });
+
+        let children = if pushes.is_empty() {
+            quote! { Vec::new() }
+        } else {
+            quote! {
+                let mut children = Vec::new();
+                #pushes
+                children
+            }
+        };
+
+        quote! {
+            impl #params #name #args #where_clause {
+                #allow
+                pub fn children #method_params(&'n self #arena_param) -> impl Iterator<Item = NodeRef #n_args> #method_where {
+                    let children: Vec<NodeRef #n_args> = { #children };
+                    children.into_iter()
+                }
+
+                pub fn iter_preorder #method_params(&'n self #arena_param) -> Preorder #n_args #method_where {
+                    NodeRef::from(self).iter_preorder(#arena)
+                }
+
+                pub fn iter_postorder #method_params(&'n self #arena_param) -> Postorder #n_args #method_where {
+                    NodeRef::from(self).iter_postorder(#arena)
+                }
+
+                pub fn iter_bfs #method_params(&'n self #arena_param) -> BreadthFirst #n_args #method_where {
+                    NodeRef::from(self).iter_bfs(#arena)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    #[test]
+    fn node_ref() {
+        let context: Context = parse_quote! {
+            Lit |isize|,
+            Expr: enum Expr {
+                |Lit|,
+                Call: struct Call {
+                    args: Vec<Expr>,
+                },
+                Unit,
+            }
+        };
+
+        let nodes = Nodes::new(&context);
+        let impl_tokens = nodes.create_nodes();
+
+        println!("{}", impl_tokens);
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::{HashMap, HashSet};
+
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{GenericArgument, Ident, PathArguments, Type};
+
+/// How a field or variant type reaches the new types, if it does at all.
+#[derive(Debug, Clone)]
+pub enum Shape {
+    /// One of the new types, by name.
+    Node(Ident),
+    /// An arena handle to one of the new types, by the name of that type.
+    Id(Ident),
+    Box(Box<Shape>),
+    Option(Box<Shape>),
+    Vec(Box<Shape>),
+    /// A type that contains none of the new types.
+    Leaf,
+}
+
+impl Shape {
+    pub fn of(ty: &Type, new_idents: &HashSet<String>) -> Self {
+        Self::resolving(ty, new_idents, &HashMap::new())
+    }
+
+    /// Like `of`, but also sees through the arena handles in `ids`, which maps
+    /// the name of each handle type to the new type it refers to.
+    pub fn resolving(
+        ty: &Type,
+        new_idents: &HashSet<String>,
+        ids: &HashMap<String, Ident>,
+    ) -> Self {
+        let path = match ty {
+            Type::Paren(p) => return Self::resolving(&p.elem, new_idents, ids),
+            Type::Group(g) => return Self::resolving(&g.elem, new_idents, ids),
+            Type::Path(p) if p.qself.is_none() => &p.path,
+            _ => return Shape::Leaf,
+        };
+
+        let segment = match path.segments.last() {
+            Some(segment) => segment,
+            None => return Shape::Leaf,
+        };
+
+        // A new type is a node whatever its generic arguments
+        let name = segment.ident.to_string();
+        if path.segments.len() == 1 && new_idents.contains(&name) {
+            return Shape::Node(segment.ident.clone());
+        }
+
+        match &segment.arguments {
+            PathArguments::None => {
+                if path.segments.len() != 1 {
+                    Shape::Leaf
+                } else if let Some(node) = ids.get(&name) {
+                    Shape::Id(node.clone())
+                } else {
+                    Shape::Leaf
+                }
+            }
+            PathArguments::AngleBracketed(args) if args.args.len() == 1 => {
+                let inner = match args.args.first() {
+                    Some(GenericArgument::Type(inner)) => Self::resolving(inner, new_idents, ids),
+                    _ => return Shape::Leaf,
+                };
+
+                if inner.is_leaf() {
+                    return Shape::Leaf;
+                }
+
+                match segment.ident.to_string().as_str() {
+                    "Box" => Shape::Box(Box::new(inner)),
+                    "Option" => Shape::Option(Box::new(inner)),
+                    "Vec" => Shape::Vec(Box::new(inner)),
+                    _ => Shape::Leaf,
+                }
+            }
+            _ => Shape::Leaf,
+        }
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        matches!(self, Shape::Leaf)
+    }
+
+    /// Statements that call `visit` on every node reachable through the
+    /// reference expression `expr`. Arena handles are resolved through the
+    /// `AstArena` reference expression `arena`, and with `mutable` each node
+    /// behind one is visited as a copy that is then put back in its place.
+    pub fn visit(
+        &self,
+        expr: TokenStream,
+        mutable: bool,
+        arena: &TokenStream,
+        visit: &dyn Fn(&Ident, TokenStream) -> TokenStream,
+    ) -> TokenStream {
+        match self {
+            Shape::Node(name) => visit(name, expr),
+            Shape::Id(name) if mutable => {
+                let body = visit(name, quote! { &mut node });
+
+                quote! {
+                    {
+                        let id = *#expr;
+                        let mut node = #arena.get(id).clone();
+                        #body
+                        *#arena.get_mut(id) = node;
+                    }
+                }
+            }
+            Shape::Id(name) => visit(name, quote! { #arena.get(*#expr) }),
+            Shape::Box(inner) => {
+                let deref = if mutable {
+                    quote! { &mut **#expr }
+                } else {
+                    quote! { &**#expr }
+                };
+
+                inner.visit(deref, mutable, arena, visit)
+            }
+            Shape::Option(inner) => {
+                let body = inner.visit(quote! { v }, mutable, arena, visit);
+
+                quote! {
+                    if let Some(v) = #expr {
+                        #body
+                    }
+                }
+            }
+            Shape::Vec(inner) => {
+                let body = inner.visit(quote! { v }, mutable, arena, visit);
+
+                quote! {
+                    for v in #expr {
+                        #body
+                    }
+                }
+            }
+            Shape::Leaf => TokenStream::new(),
+        }
+    }
+
+    /// An expression that rebuilds the owned value `expr`, passing every
+    /// node reachable through it to `fold`. The node behind an arena handle
+    /// is folded as a copy that replaces it in the mutable `AstArena`
+    /// reference expression `arena`, and the handle is kept.
+    pub fn fold(
+        &self,
+        expr: TokenStream,
+        arena: &TokenStream,
+        fold: &dyn Fn(&Ident, TokenStream) -> TokenStream,
+    ) -> TokenStream {
+        match self {
+            Shape::Node(name) => fold(name, expr),
+            Shape::Id(name) => {
+                let body = fold(name, quote! { node });
+
+                quote! {
+                    {
+                        let id = #expr;
+                        let node = #arena.get(id).clone();
+                        let node = #body;
+                        *#arena.get_mut(id) = node;
+                        id
+                    }
+                }
+            }
+            Shape::Box(inner) => {
+                let body = inner.fold(quote! { *#expr }, arena, fold);
+
+                quote! { Box::new(#body) }
+            }
+            Shape::Option(inner) => {
+                let body = inner.fold(quote! { v }, arena, fold);
+
+                quote! { #expr.map(|v| #body) }
+            }
+            Shape::Vec(inner) => {
+                let body = inner.fold(quote! { v }, arena, fold);
+
+                quote! { #expr.into_iter().map(|v| #body).collect() }
+            }
+            Shape::Leaf => expr,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    #[test]
+    fn nested_shapes() {
+        let new_idents: HashSet<String> = vec!["Expr".to_string()].into_iter().collect();
+
+        let ty: Type = parse_quote! { Expr };
+        assert!(matches!(Shape::of(&ty, &new_idents), Shape::Node(_)));
+
+        let ty: Type = parse_quote! { Vec<Option<Box<Expr>>> };
+        assert!(matches!(Shape::of(&ty, &new_idents), Shape::Vec(_)));
+
+        let ty: Type = parse_quote! { Vec<String> };
+        assert!(Shape::of(&ty, &new_idents).is_leaf());
+
+        let ty: Type = parse_quote! { HashMap<Expr> };
+        assert!(Shape::of(&ty, &new_idents).is_leaf());
+
+        let ids: HashMap<String, Ident> = vec![("ExprId".to_string(), parse_quote! { Expr })]
+            .into_iter()
+            .collect();
+        let ty: Type = parse_quote! { Option<ExprId> };
+        assert!(Shape::of(&ty, &new_idents).is_leaf());
+        assert!(matches!(
+            Shape::resolving(&ty, &new_idents, &ids),
+            Shape::Option(_)
+        ));
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::HashSet;
+
+use proc_macro2::TokenStream;
+use quote::{quote, quote_spanned, TokenStreamExt};
+use syn::{spanned::Spanned, Type};
+
+use crate::context::{Context, EnumType, NewType};
+use crate::shape::Shape;
+
+/// The source spans of span mode.
+///
+/// Structs and wrappers store their span in an `IgnoredSpan`, which is equal
+/// to every other and hashes to nothing, so that equality and hashing derived
+/// for the nodes only compare their contents. Enums have no span of their own
+/// and return that of their variant, if it has one.
+pub struct Spans<'c> {
+    pub new_idents: HashSet<String>,
+    context: &'c Context,
+}
+
+impl<'c> Spans<'c> {
+    pub fn new(context: &'c Context) -> Self {
+        let new_idents: HashSet<String> = context
+            .new_types
+            .iter()
+            .map(|n| n.name().to_string())
+            .collect();
+
+        Self {
+            new_idents,
+            context,
+        }
+    }
+
+    pub fn create_spans(&self) -> TokenStream {
+        let mut tokens = TokenStream::default();
+
+        if !self.context.spans {
+            return tokens;
+        }
+
+        let serde = self.context.serde_derive();
+        let transparent = if serde.is_empty() {
+            TokenStream::new()
+        } else {
+            quote! { #[serde(transparent)] }
+        };
+
+        tokens.append_all(match &self.context.span_type {
+            // The span of a node built without one, like through `From`, is
+            // the default
+            Some(ty) => quote_spanned! {ty.span()=>
+                pub type Span = #ty;
+
+                const _: fn() = || {
+                    fn span_bounds<T: Clone + std::fmt::Debug + Default>() {}
+                    span_bounds::<#ty>();
+                };
+            },
+            None => quote! {
+                /// The byte offsets of a node in its source.
+                #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+                #serde
+                pub struct Span {
+                    pub start: usize,
+                    pub end: usize,
+                }
+
+                impl Span {
+                    pub fn new(start: usize, end: usize) -> Self {
+                        Self { start, end }
+                    }
+                }
+            },
+        });
+
+        // Any unmet bound of a given span type is reported at that type
+        let span = match &self.context.span_type {
+            Some(ty) => quote_spanned! {ty.span()=> Span },
+            None => quote! { Span },
+        };
+
+        tokens.append_all(quote! {
+            #[doc(hidden)]
+            #[derive(Clone, Default)]
+            #serde
+            #transparent
+            pub struct IgnoredSpan(#span);
+
+            impl std::fmt::Debug for IgnoredSpan {
+                fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                    <#span as std::fmt::Debug>::fmt(&self.0, f)
+                }
+            }
+
+            impl PartialEq for IgnoredSpan {
+                fn eq(&self, _: &Self) -> bool {
+                    true
+                }
+            }
+
+            impl Eq for IgnoredSpan {}
+
+            impl PartialOrd for IgnoredSpan {
+                fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+                    Some(self.cmp(other))
+                }
+            }
+
+            impl Ord for IgnoredSpan {
+                fn cmp(&self, _: &Self) -> std::cmp::Ordering {
+                    std::cmp::Ordering::Equal
+                }
+            }
+
+            impl std::hash::Hash for IgnoredSpan {
+                fn hash<H: std::hash::Hasher>(&self, _: &mut H) {}
+            }
+        });
+
+        let root_type = self.context.root_type();
+
+        for new_type in root_type.iter().chain(&self.context.new_types) {
+            tokens.append_all(self.accessors(new_type));
+        }
+
+        tokens
+    }
+
+    /// `span`, `span_mut` and `with_span` for a struct or wrapper, and `span`
+    /// for an enum.
+    fn accessors(&self, new_type: &NewType) -> TokenStream {
+        let name = new_type.name();
+        let (params, args, where_clause) = new_type.generics().split_for_impl();
+        let field = match new_type {
+            NewType::Struct(_) => quote! { self.span.0 },
+            NewType::WrapperStruct(_) => quote! { self.1.0 },
+            NewType::Enum(e) => return self.enum_span(e),
+        };
+
+        quote! {
+            impl #params #name #args #where_clause {
+                pub fn span(&self) -> &Span {
+                    &#field
+                }
+
+                pub fn span_mut(&mut self) -> &mut Span {
+                    &mut #field
+                }
+
+                pub fn with_span(mut self, span: Span) -> Self {
+                    #field = span;
+                    self
+                }
+            }
+        }
+    }
+
+    /// An enum's `span`, which is that of its variant, or `None` for a
+    /// variant without a node.
+    fn enum_span(&self, e: &EnumType) -> TokenStream {
+        let name = &e.name;
+        let (params, args, where_clause) = e.generics.split_for_impl();
+
+        let arms = e.variants.iter().map(|v| {
+            let variant = &v.name;
+            let cfg = v.cfg();
+
+            match v.ty.as_ref().and_then(|ty| self.variant_span(ty)) {
+                Some(true) => quote! { #cfg #name::#variant(v) => v.span(), },
+                Some(false) => quote! { #cfg #name::#variant(v) => Some(v.span()), },
+                None if v.ty.is_some() => quote! { #cfg #name::#variant(_) => None, },
+                None if v.fields.is_some() => quote! { #cfg #name::#variant { .. } => None, },
+                None => quote! { #cfg #name::#variant => None, },
+            }
+        });
+
+        quote! {
+            impl #params #name #args #where_clause {
+                pub fn span(&self) -> Option<&Span> {
+                    match self {
+                        #(#arms)*
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether a variant of type `ty` has a span, and if so, whether it is
+    /// that of an enum, which may have none. Only nodes, boxed or not, have
+    /// spans.
+    fn variant_span(&self, ty: &Type) -> Option<bool> {
+        let node = match Shape::of(ty, &self.new_idents) {
+            Shape::Node(node) => node,
+            Shape::Box(inner) => match *inner {
+                Shape::Node(node) => node,
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        let new_type = self
+            .context
+            .new_types
+            .iter()
+            .find(|nt| *nt.name() == node)?;
+
+        Some(matches!(new_type, NewType::Enum(_)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    #[test]
+    fn enum_spans() {
+        let context: Context = parse_quote! {
+            #![span]
+            Lit |isize|,
+            Expr: enum Expr {
+                Call: struct Call {
+                    args: Vec<Expr>,
+                },
+                Neg(Box<Expr>),
+                |Lit|,
+            },
+            Stmt: enum Stmt {
+                |Expr|,
+                Print(Option<Expr>),
+                Empty,
+            }
+        };
+
+        let spans = Spans::new(&context);
+        let impl_tokens = spans.create_spans();
+        let compact = impl_tokens.to_string().replace(' ', "");
+
+        assert!(compact.contains("implExpr{pubfnspan(&self)->Option<&Span>"));
+        assert!(compact.contains("implStmt{pubfnspan(&self)->Option<&Span>"));
+        assert!(compact.contains("Expr::Neg(v)=>v.span(),"));
+        assert!(compact.contains("Expr::Call(v)=>Some(v.span()),"));
+        assert!(compact.contains("Stmt::Print(_)=>None,"));
+
+        println!("{}", impl_tokens);
+    }
+
+    #[test]
+    fn given_type() {
+        let context: Context = parse_quote! {
+            #![span(crate::Loc)]
+            Lit |isize|,
+        };
+
+        let impl_tokens = Spans::new(&context).create_spans();
+        let compact = impl_tokens.to_string().replace(' ', "");
+
+        assert!(compact.contains("pubtypeSpan=crate::Loc;"));
+        assert!(compact.contains("span_bounds::<crate::Loc>()"));
+    }
+}
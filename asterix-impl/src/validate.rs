@@ -0,0 +1,826 @@
+use std::collections::HashSet;
+
+use quote::{quote, ToTokens};
+use syn::{
+    punctuated::Punctuated, GenericArgument, Ident, NestedMeta, PathArguments, Result, Token, Type,
+};
+
+use crate::context::{box_to_id, param_ident, Context, Field, NewType, Variant, VariantFields};
+use crate::shape::Shape;
+
+/// The types every grammar can name without a path, since the generated
+/// module only sees the prelude besides its own types.
+const BUILTIN: &[&str] = &[
+    "bool", "char", "str", "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32", "i64",
+    "i128", "isize", "f32", "f64", "Box", "Option", "Result", "String", "Vec", "Self",
+];
+
+/// The items generated next to the types of every grammar, besides the
+/// visitors, and the names whose hooks or `walk_*` function would be the
+/// catch-all hooks `enter_any` and `leave_any`, or `walk_iterative`.
+const SUPPORT: &[&str] = &[
+    "VisitAction",
+    "NodeRef",
+    "Preorder",
+    "Postorder",
+    "BreadthFirst",
+    "AstNode",
+    "Descendants",
+    "Fold",
+    "Any",
+    "Iterative",
+];
+
+/// Reports the mistakes in a grammar that would otherwise surface as errors
+/// inside the generated module: names declared twice or taken by the
+/// generated items and fields, variants that can't be internally tagged,
+/// types that are not declared, types that contain themselves, and variants
+/// whose `From` impls overlap. Runs before
+/// the grammar is rewritten, so the errors point at what the user wrote.
+/// Besides its own and the generated ones it may use, the grammar can name
+/// the types `in_scope`, or any type with `None`.
+pub fn validate(context: &Context, in_scope: Option<&[Ident]>) -> Result<()> {
+    let mut errors: Vec<syn::Error> = Vec::new();
+
+    duplicate_names(context, &mut errors);
+    reserved_names(context, &mut errors);
+    injected_fields(context, &mut errors);
+    internal_tags(context, &mut errors);
+    if let Some(in_scope) = in_scope {
+        undefined_types(context, in_scope, &mut errors);
+    }
+    infinite_types(context, &mut errors);
+    duplicate_froms(context, &mut errors);
+
+    let mut errors = errors.into_iter();
+    match errors.next() {
+        Some(mut error) => {
+            errors.for_each(|e| error.combine(e));
+            Err(error)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Every type with the variants or fields it holds, the root first.
+fn holders(context: &Context) -> Vec<(&Ident, Vec<&Type>)> {
+    let root = context.root.iter().map(|root| {
+        (
+            root,
+            context.variants.iter().flat_map(|v| v.types()).collect(),
+        )
+    });
+    let new_types = context.new_types.iter().map(|nt| {
+        let types = match nt {
+            NewType::Enum(e) => e.variants.iter().flat_map(|v| v.types()).collect(),
+            NewType::Struct(s) => s.fields.iter().map(|f| &f.ty).collect(),
+            NewType::WrapperStruct(w) => vec![&w.ty],
+        };
+        (nt.name(), types)
+    });
+
+    root.chain(new_types).collect()
+}
+
+/// Every enum with its variants, the root first.
+fn enums(context: &Context) -> Vec<&Punctuated<Variant, Token![,]>> {
+    let new_types = context.new_types.iter().filter_map(|nt| match nt {
+        NewType::Enum(e) => Some(&e.variants),
+        _ => None,
+    });
+
+    context
+        .root
+        .iter()
+        .map(|_| &context.variants)
+        .chain(new_types)
+        .collect()
+}
+
+fn duplicate_names(context: &Context, errors: &mut Vec<syn::Error>) {
+    let mut declared = HashSet::new();
+    let mut twice = HashSet::new();
+    for name in context.node_names() {
+        if !declared.insert(name.to_string()) {
+            errors.push(syn::Error::new(
+                name.span(),
+                format!("`{}` is declared more than once", name),
+            ));
+            twice.insert(name.to_string());
+        }
+    }
+
+    // The top-level entries declaring a type twice are reported above
+    for variants in enums(context) {
+        let mut seen = HashSet::new();
+        for variant in variants {
+            let name = variant.name.to_string();
+            if !seen.insert(name.clone()) && !twice.contains(&name) {
+                errors.push(syn::Error::new(
+                    variant.name.span(),
+                    format!("duplicate variant `{}`", variant.name),
+                ));
+            }
+        }
+    }
+
+    let struct_fields = context.new_types.iter().filter_map(|nt| match nt {
+        NewType::Struct(s) => Some(s.fields.iter().collect::<Vec<_>>()),
+        _ => None,
+    });
+    let variant_fields = enums(context)
+        .into_iter()
+        .flatten()
+        .filter_map(|v| match &v.fields {
+            Some(VariantFields::Named(fields)) => Some(fields.iter().collect()),
+            _ => None,
+        });
+    for fields in struct_fields.chain(variant_fields) {
+        let mut seen = HashSet::new();
+        for field in fields {
+            if !seen.insert(field.ident.to_string()) {
+                errors.push(syn::Error::new(
+                    field.ident.span(),
+                    format!("duplicate field `{}`", field.ident),
+                ));
+            }
+        }
+    }
+}
+
+/// The names of the items generated for `context`, which its types can't
+/// take.
+fn generated_names(context: &Context) -> Vec<String> {
+    let visitor = &context.visitor;
+    let mut names: Vec<String> = SUPPORT.iter().map(|name| name.to_string()).collect();
+    names.extend(vec![
+        visitor.to_string(),
+        format!("{}Mut", visitor),
+        format!("Exhaustive{}", visitor),
+        format!("Try{}", visitor),
+        format!("Ctx{}", visitor),
+        format!("Typed{}", visitor),
+    ]);
+
+    if context.spans {
+        names.extend(vec!["Span".to_string(), "IgnoredSpan".to_string()]);
+    }
+    if context.arena {
+        names.extend(vec!["AstArena".to_string(), "ArenaId".to_string()]);
+        names.extend(arena_ids(context));
+    }
+    if context.stack_safe_drop {
+        names.push("Detached".to_string());
+    }
+    if let Some(ann) = &context.annotation {
+        names.extend(vec![
+            ann.to_string(),
+            "MapAnnotations".to_string(),
+            "AnnotationMap".to_string(),
+            format!("New{}", ann),
+        ]);
+    }
+
+    names
+}
+
+/// The generated types that the grammar may name: the span, and the arena
+/// with the ids into it.
+fn nameable(context: &Context) -> Vec<String> {
+    let mut names = Vec::new();
+
+    if context.spans {
+        names.push("Span".to_string());
+    }
+    if context.arena {
+        names.push("AstArena".to_string());
+        names.extend(arena_ids(context));
+    }
+
+    names
+}
+
+/// `TId` for every new type `T` that arena mode gives ids, those boxed
+/// somewhere in the grammar.
+fn arena_ids(context: &Context) -> Vec<String> {
+    let new_idents: Vec<String> = context
+        .new_types
+        .iter()
+        .map(|nt| nt.name().to_string())
+        .collect();
+
+    let mut boxed = Vec::new();
+    for (_, types) in holders(context) {
+        for ty in types {
+            box_to_id(&mut ty.clone(), &new_idents, &mut boxed);
+        }
+    }
+
+    boxed.iter().map(|name| format!("{}Id", name)).collect()
+}
+
+fn reserved_names(context: &Context, errors: &mut Vec<syn::Error>) {
+    let generated = generated_names(context);
+    for name in context.node_names() {
+        if generated.contains(&name.to_string()) {
+            errors.push(syn::Error::new(
+                name.span(),
+                format!(
+                    "`{}` is the name of an item generated for the grammar, rename the type",
+                    name
+                ),
+            ));
+        }
+    }
+}
+
+/// Reports the fields named like those the options add: the `span` of each
+/// struct, and the `annotation` of each struct and named variant.
+fn injected_fields(context: &Context, errors: &mut Vec<syn::Error>) {
+    let struct_fields: Vec<&Field> = context
+        .new_types
+        .iter()
+        .flat_map(|nt| match nt {
+            NewType::Struct(s) => s.fields.iter().collect(),
+            _ => Vec::new(),
+        })
+        .collect();
+    let variant_fields: Vec<&Field> = enums(context)
+        .into_iter()
+        .flatten()
+        .flat_map(|v| match &v.fields {
+            Some(VariantFields::Named(fields)) => fields.iter().collect(),
+            _ => Vec::new(),
+        })
+        .collect();
+
+    for field in &struct_fields {
+        if context.spans && field.ident == "span" {
+            errors.push(syn::Error::new(
+                field.ident.span(),
+                "`#![span]` gives every struct a `span` field, rename the field",
+            ));
+        }
+    }
+
+    for field in struct_fields.iter().chain(&variant_fields) {
+        if context.annotation.is_some() && field.ident == "annotation" {
+            errors.push(syn::Error::new(
+                field.ident.span(),
+                "`#![annotated]` gives every struct and named variant an `annotation` field, rename the field",
+            ));
+        }
+    }
+}
+
+/// Reports the variants serde can't tag internally, under a `tag` without a
+/// `content`. An internally tagged variant is written as a map holding the
+/// tag, so it can only hold named fields or a struct of them, in place rather
+/// than behind an arena id. A nested enum would write its own tag in the map.
+fn internal_tags(context: &Context, errors: &mut Vec<syn::Error>) {
+    let serde = match &context.serde {
+        Some(serde) => serde,
+        None => return,
+    };
+    let named = |name: &str| {
+        serde.iter().any(|arg| match arg {
+            NestedMeta::Meta(meta) => meta.path().is_ident(name),
+            NestedMeta::Lit(_) => false,
+        })
+    };
+    if !named("tag") || named("content") {
+        return;
+    }
+
+    let new_idents: HashSet<String> = holders(context)
+        .iter()
+        .map(|(name, _)| name.to_string())
+        .collect();
+    let is_struct = |node: &Ident| {
+        context
+            .new_types
+            .iter()
+            .any(|nt| matches!(nt, NewType::Struct(s) if s.name == *node))
+    };
+
+    for variant in enums(context).into_iter().flatten() {
+        let tagged = match (&variant.ty, &variant.fields) {
+            (Some(ty), _) => match Shape::of(ty, &new_idents) {
+                Shape::Node(node) => is_struct(&node),
+                Shape::Box(inner) if !context.arena => match *inner {
+                    Shape::Node(node) => is_struct(&node),
+                    _ => false,
+                },
+                _ => false,
+            },
+            (None, Some(VariantFields::Unnamed(_))) => false,
+            (None, _) => true,
+        };
+
+        if !tagged {
+            errors.push(syn::Error::new(
+                variant.name.span(),
+                format!(
+                    "`{}` can't be internally tagged, since it holds something other than a struct with named fields; give `tag` a `content` to tag the enums adjacently",
+                    variant.name
+                ),
+            ));
+        }
+    }
+}
+
+fn undefined_types(context: &Context, in_scope: &[Ident], errors: &mut Vec<syn::Error>) {
+    let mut known: HashSet<String> = BUILTIN.iter().map(|name| name.to_string()).collect();
+    known.extend(in_scope.iter().map(|name| name.to_string()));
+    known.extend(context.node_names().iter().map(|name| name.to_string()));
+    known.extend(context.annotation.iter().map(|ann| ann.to_string()));
+    known.extend(nameable(context));
+
+    let params = |new_type: &NewType| -> Vec<String> {
+        new_type
+            .generics()
+            .params
+            .iter()
+            .map(|param| param_ident(param).to_string())
+            .collect()
+    };
+
+    for (name, types) in holders(context) {
+        // Each type names its own parameters, and the root those of every
+        // type, which it takes as it needs them
+        let mut known = known.clone();
+        match context.new_types.iter().find(|nt| nt.name() == name) {
+            Some(new_type) => known.extend(params(new_type)),
+            None => known.extend(context.new_types.iter().flat_map(params)),
+        }
+
+        for ty in types {
+            undefined(ty, &known, errors);
+        }
+    }
+}
+
+/// Reports every name in `ty` that is written without a path and isn't
+/// `known`.
+fn undefined(ty: &Type, known: &HashSet<String>, errors: &mut Vec<syn::Error>) {
+    let path = match ty {
+        Type::Paren(p) => return undefined(&p.elem, known, errors),
+        Type::Group(g) => return undefined(&g.elem, known, errors),
+        Type::Reference(r) => return undefined(&r.elem, known, errors),
+        Type::Array(a) => return undefined(&a.elem, known, errors),
+        Type::Slice(s) => return undefined(&s.elem, known, errors),
+        Type::Tuple(t) => {
+            for elem in &t.elems {
+                undefined(elem, known, errors);
+            }
+            return;
+        }
+        Type::Path(p) if p.qself.is_none() => &p.path,
+        _ => return,
+    };
+
+    if path.leading_colon.is_none() && path.segments.len() == 1 {
+        let name = &path.segments[0].ident;
+        if !known.contains(&name.to_string()) {
+            errors.push(syn::Error::new(
+                name.span(),
+                format!(
+                    "cannot find type `{}` in this ast!, name types declared elsewhere by their path",
+                    name
+                ),
+            ));
+        }
+    }
+
+    for segment in &path.segments {
+        if let PathArguments::AngleBracketed(args) = &segment.arguments {
+            for arg in &args.args {
+                if let GenericArgument::Type(inner) = arg {
+                    undefined(inner, known, errors);
+                }
+            }
+        }
+    }
+}
+
+/// The new types that `ty` holds in place, without a `Box` or `Vec` between
+/// them, including those in its tuples and arrays.
+fn in_place(ty: &Type, new_idents: &HashSet<String>) -> Vec<String> {
+    match ty {
+        Type::Paren(p) => in_place(&p.elem, new_idents),
+        Type::Group(g) => in_place(&g.elem, new_idents),
+        Type::Array(a) => in_place(&a.elem, new_idents),
+        Type::Tuple(t) => t
+            .elems
+            .iter()
+            .flat_map(|elem| in_place(elem, new_idents))
+            .collect(),
+        _ => match Shape::of(ty, new_idents) {
+            Shape::Node(name) => vec![name.to_string()],
+            Shape::Option(_) => argument(ty)
+                .map(|inner| in_place(inner, new_idents))
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        },
+    }
+}
+
+/// The type argument of a path type like `Option<T>`.
+fn argument(ty: &Type) -> Option<&Type> {
+    let segment = match ty {
+        Type::Path(p) => p.path.segments.last()?,
+        _ => return None,
+    };
+
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) => args.args.iter().find_map(|arg| match arg {
+            GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        }),
+        _ => None,
+    }
+}
+
+fn infinite_types(context: &Context, errors: &mut Vec<syn::Error>) {
+    let holders = holders(context);
+    let new_idents: HashSet<String> = holders.iter().map(|(name, _)| name.to_string()).collect();
+
+    // Each type, with every type it holds in place and where it does
+    let edges: Vec<(String, Vec<(String, &Type)>)> = holders
+        .iter()
+        .map(|(name, types)| {
+            let held = types
+                .iter()
+                .flat_map(|ty| {
+                    in_place(ty, &new_idents)
+                        .into_iter()
+                        .map(move |node| (node, *ty))
+                })
+                .collect();
+            (name.to_string(), held)
+        })
+        .collect();
+    let held = |name: &str| {
+        edges
+            .iter()
+            .find(|(holder, _)| holder == name)
+            .map(|(_, held)| held.as_slice())
+            .unwrap_or_default()
+    };
+
+    // A cycle is reported once, at the first of its types to be declared
+    let mut reported: HashSet<String> = HashSet::new();
+    for (name, held_here) in &edges {
+        if reported.contains(name) {
+            continue;
+        }
+
+        for (node, ty) in held_here {
+            let mut path = vec![name.clone()];
+            if !reaches(node, name, &held, &mut path) {
+                continue;
+            }
+
+            errors.push(syn::Error::new_spanned(
+                ty,
+                format!(
+                    "`{}` holds itself through `{}` and so has no size, put it behind a `Box`",
+                    name,
+                    ty.to_token_stream()
+                ),
+            ));
+            reported.extend(path);
+            break;
+        }
+    }
+}
+
+/// Whether `to` is held in place by `from`, or by the types held in place by
+/// it, recording the types on the way in `path`.
+fn reaches<'e>(
+    from: &str,
+    to: &str,
+    held: &impl Fn(&str) -> &'e [(String, &'e Type)],
+    path: &mut Vec<String>,
+) -> bool {
+    if from == to {
+        return true;
+    }
+    if path.iter().any(|seen| seen == from) {
+        return false;
+    }
+
+    path.push(from.to_string());
+    if held(from)
+        .iter()
+        .any(|(node, _)| reaches(node, to, held, path))
+    {
+        return true;
+    }
+    path.pop();
+
+    false
+}
+
+fn duplicate_froms(context: &Context, errors: &mut Vec<syn::Error>) {
+    for variants in enums(context) {
+        let mut seen: Vec<(String, &Ident)> = Vec::new();
+
+        for variant in variants {
+            // A variant declared twice is reported as such
+            if seen.iter().any(|(_, first)| **first == variant.name) {
+                continue;
+            }
+
+            let from = match (&variant.ty, &variant.fields) {
+                (Some(ty), _) => ty.to_token_stream(),
+                (_, Some(VariantFields::Unnamed(types))) if types.len() > 1 => {
+                    quote! { (#types) }
+                }
+                _ => continue,
+            };
+            let from = from.to_string();
+
+            match seen.iter().find(|(ty, _)| *ty == from) {
+                Some((_, first)) => errors.push(syn::Error::new(
+                    variant.name.span(),
+                    format!(
+                        "`{}` and `{}` both hold `{}`, so only one of them can be built `From` it",
+                        first, variant.name, from
+                    ),
+                )),
+                None => seen.push((from, &variant.name)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proc_macro2::TokenStream;
+
+    fn errors(tokens: TokenStream) -> Vec<String> {
+        match syn::parse2::<Context>(tokens) {
+            Ok(_) => Vec::new(),
+            Err(error) => error.into_iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn valid() {
+        let errors = errors(quote! {
+            Name |String|,
+            Expr: enum Expr<A> {
+                Call(Name, Vec<Expr>),
+                Neg(Box<Expr>),
+                Cond: struct Cond {
+                    then: Option<Box<Expr>>,
+                    ret: (Name, std::rc::Rc<Expr>),
+                },
+                Lit(A),
+            },
+        });
+
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    #[test]
+    fn duplicates() {
+        let errors = errors(quote! {
+            Expr: enum Expr {
+                Lit(isize),
+                Lit,
+                Let { name: String, name: String },
+            },
+            Expr: struct Expr {},
+        });
+
+        assert_eq!(
+            errors,
+            vec![
+                "`Expr` is declared more than once",
+                "duplicate variant `Lit`",
+                "duplicate field `name`",
+            ]
+        );
+    }
+
+    #[test]
+    fn reserved() {
+        let errors = errors(quote! {
+            #![visitor = TreeVisitor]
+            Tree: enum Tree {
+                Node: struct Node { kids: Vec<Tree> },
+                Leaf(isize),
+                Ref: NodeRef |Box<Tree>|,
+                Fold: struct Fold {},
+            },
+            TryTreeVisitor |String|,
+            Any |isize|,
+            Iterative: struct Iterative {},
+        });
+
+        assert_eq!(
+            errors,
+            vec![
+                "`NodeRef` is the name of an item generated for the grammar, rename the type",
+                "`Fold` is the name of an item generated for the grammar, rename the type",
+                "`TryTreeVisitor` is the name of an item generated for the grammar, rename the type",
+                "`Any` is the name of an item generated for the grammar, rename the type",
+                "`Iterative` is the name of an item generated for the grammar, rename the type",
+            ]
+        );
+    }
+
+    #[test]
+    fn reserved_by_options() {
+        let options = errors(quote! {
+            #![span]
+            #![arena]
+            #![stack_safe_drop]
+            Expr: enum Expr {
+                Neg(Box<Expr>),
+                Span: struct Span {},
+                ExprId |usize|,
+                Detached |usize|,
+            },
+        });
+        let message = |name: &str| {
+            format!(
+                "`{}` is the name of an item generated for the grammar, rename the type",
+                name
+            )
+        };
+
+        assert_eq!(
+            options,
+            vec![message("Span"), message("ExprId"), message("Detached")]
+        );
+
+        let annotated = errors(quote! {
+            #![annotated(Info)]
+            Expr: enum Expr {
+                Lit(isize),
+                NewInfo |isize|,
+                AnnotationMap |isize|,
+                Info |usize|,
+            },
+        });
+
+        assert_eq!(
+            annotated,
+            vec![
+                message("NewInfo"),
+                message("AnnotationMap"),
+                message("Info")
+            ]
+        );
+    }
+
+    #[test]
+    fn injected_fields() {
+        let spans = errors(quote! {
+            #![span]
+            Expr: enum Expr {
+                Call: struct Call {
+                    span: (usize, usize),
+                },
+                Let { span: usize },
+            },
+        });
+
+        assert_eq!(
+            spans,
+            vec!["`#![span]` gives every struct a `span` field, rename the field"]
+        );
+
+        let annotated = errors(quote! {
+            #![annotated]
+            Expr: enum Expr {
+                Call: struct Call {
+                    annotation: String,
+                },
+                Let { name: String, annotation: String },
+            },
+        });
+        let message = "`#![annotated]` gives every struct and named variant an `annotation` field, rename the field";
+
+        assert_eq!(annotated, vec![message, message]);
+    }
+
+    #[test]
+    fn undefined() {
+        let errors = errors(quote! {
+            Expr: enum Expr {
+                Call(Name, HashMap<String, Expr>),
+                Path(std::path::PathBuf),
+            },
+        });
+
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("cannot find type `Name`"));
+        assert!(errors[1].starts_with("cannot find type `HashMap`"));
+    }
+
+    #[test]
+    fn generated_types() {
+        let errors = errors(quote! {
+            #![span]
+            #![arena]
+            Expr: enum Expr {
+                Neg(Box<Expr>),
+                Ref(ExprId),
+                Group(StmtId),
+                At(Span),
+            },
+            Stmt: struct Stmt {
+                arena: Option<AstArena>,
+            },
+        });
+
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].starts_with("cannot find type `StmtId`"));
+    }
+
+    #[test]
+    fn generics_per_type() {
+        let errors = errors(quote! {
+            Expr: enum Expr<A> {
+                Lit(A),
+                Cond: struct Cond {
+                    then: A,
+                },
+            },
+        });
+
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].starts_with("cannot find type `A`"));
+    }
+
+    #[test]
+    fn infinite() {
+        let errors = errors(quote! {
+            Expr: enum Expr {
+                Neg(Option<Expr>),
+                Bin: struct Bin {
+                    lhs: Box<Expr>,
+                    rhs: Expr,
+                },
+            },
+            Stmt: enum Stmt {
+                |Expr|,
+                Block(Vec<Stmt>),
+            },
+        });
+
+        assert_eq!(
+            errors,
+            vec![
+                "`Expr` holds itself through `Option < Expr >` and so has no size, put it behind a `Box`",
+                "`Bin` holds itself through `Expr` and so has no size, put it behind a `Box`",
+            ]
+        );
+    }
+
+    #[test]
+    fn infinite_tuples() {
+        let errors = errors(quote! {
+            Expr: enum Expr {
+                Pair((Expr, isize)),
+                Lit(isize),
+            },
+            Grid: struct Grid {
+                cells: [Option<Grid>; 4],
+            },
+        });
+
+        assert_eq!(
+            errors,
+            vec![
+                "`Expr` holds itself through `( Expr , isize )` and so has no size, put it behind a `Box`",
+                "`Grid` holds itself through `[ Option < Grid > ; 4 ]` and so has no size, put it behind a `Box`",
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_froms() {
+        let errors = errors(quote! {
+            Stmt: enum Stmt {
+                Print(Option<String>),
+                Ret(Option<String>),
+                Pair(String, isize),
+                Swap(String, isize),
+            },
+        });
+
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors[0].starts_with("`Print` and `Ret` both hold `Option < String >`"));
+        assert!(
+            errors[1].starts_with("`Pair` and `Swap` both hold `( String , isize )`"),
+            "{:?}",
+            errors
+        );
+    }
+}
@@ -2,17 +2,75 @@ use std::collections::HashSet;
 
 use proc_macro2::{Span, TokenStream};
 use quote::{format_ident, quote, ToTokens, TokenStreamExt};
-use syn::{spanned::Spanned, Ident};
+use syn::{parse_quote, spanned::Spanned, GenericParam, Generics, Ident, Type};
 
-use crate::context::{Context, EnumType, NewType};
+use crate::context::{lowercase, outliving, Context, NewType};
+use crate::shape::Shape;
+
+/// Which of the visitor traits is generated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flavor {
+    /// `Visitor<'ast>`, over shared references.
+    Ref,
+    /// `VisitorMut`, over mutable references.
+    Mut,
+    /// `ExhaustiveVisitor<'ast>`, without default leaf methods.
+    Exhaustive,
+    /// `TryVisitor<'ast>`, returning `Result` and stopping on the first `Err`.
+    Try,
+    /// `CtxVisitor<'ast>`, passing a `&mut Self::Ctx` down the traversal.
+    Ctx,
+    /// `TypedVisitor<'ast>`, with an output type for each node type.
+    Typed,
+}
 
 pub struct Visitor<'c> {
     pub new_idents: HashSet<String>,
     context: &'c Context,
+    flavor: Flavor,
 }
 
 impl<'c> Visitor<'c> {
+    /// Generates the read-only `Visitor<'ast>` trait.
     pub fn new(context: &'c Context) -> Self {
+        Self::with_flavor(context, Flavor::Ref)
+    }
+
+    /// Generates the `VisitorMut` trait, whose methods take `&mut` nodes.
+    pub fn new_mut(context: &'c Context) -> Self {
+        Self::with_flavor(context, Flavor::Mut)
+    }
+
+    /// Generates the `ExhaustiveVisitor<'ast>` trait. Only enum dispatch and
+    /// wrappers around a single node or id have default bodies, so `Output` does
+    /// not need `Default` and every new node kind has to be handled.
+    pub fn new_exhaustive(context: &'c Context) -> Self {
+        Self::with_flavor(context, Flavor::Exhaustive)
+    }
+
+    /// Generates the `TryVisitor<'ast>` trait, whose methods return
+    /// `Result<Self::Output, Self::Error>`. The default traversal returns the
+    /// first `Err` it sees without visiting the remaining children.
+    pub fn new_try(context: &'c Context) -> Self {
+        Self::with_flavor(context, Flavor::Try)
+    }
+
+    /// Generates the `CtxVisitor<'ast>` trait, whose methods and `walk_*`
+    /// functions take a caller-supplied `ctx: &mut Self::Ctx`.
+    pub fn new_ctx(context: &'c Context) -> Self {
+        Self::with_flavor(context, Flavor::Ctx)
+    }
+
+    /// Generates the `TypedVisitor<'ast>` trait, which has a separate
+    /// `{Name}Output` type for every node type. An enum or wrapper passes the
+    /// output of a node it holds through a required `{name}_from_{node}` hook.
+    /// As in `ExhaustiveVisitor`, every other method is required, so no output
+    /// needs `Default`.
+    pub fn new_typed(context: &'c Context) -> Self {
+        Self::with_flavor(context, Flavor::Typed)
+    }
+
+    fn with_flavor(context: &'c Context, flavor: Flavor) -> Self {
         let new_idents: HashSet<String> = context
             .new_types
             .iter()
@@ -22,214 +80,927 @@ impl<'c> Visitor<'c> {
         Self {
             new_idents,
             context,
+            flavor,
         }
     }
 
     pub fn create_visitor(&self) -> TokenStream {
         let mut tokens = TokenStream::default();
 
-        let func_impl = self.func_impl();
+        let (func_impl, walk_impl) = self.func_impl();
+
+        // In arena mode, the visitors resolve ids through the arena
+        let arena_type = self.context.arena_type();
+        let arena = if !self.context.arena {
+            TokenStream::new()
+        } else if self.mutable() {
+            quote! {
+                /// The arena that the ids in the visited nodes point into. The
+                /// node behind an id is visited as a copy, which then replaces
+                /// it.
+                fn arena_mut(&mut self) -> &mut #arena_type;
+            }
+        } else {
+            quote! {
+                /// The arena that the ids in the visited nodes point into.
+                fn arena(&self) -> &'ast #arena_type;
+            }
+        };
 
-        tokens.append_all(quote! {
-            pub trait Visitor<'ast> where Self::Output: Default {
-                type Output;
+        let trait_name = self.trait_name();
+        let mut generics = self.trait_generics();
+        if self.flavor != Flavor::Exhaustive && self.flavor != Flavor::Typed {
+            generics
+                .make_where_clause()
+                .predicates
+                .push(parse_quote! { Self::Output: Default });
+        }
+        let (trait_params, _, trait_where) = generics.split_for_impl();
+        let node_ref = self.node_ref();
 
-                #func_impl
+        let combine = quote! {
+            /// Merges the outputs of two children of the same node.
+            /// Keeps the first, which starts out as `Output::default()`.
+            #[allow(unused_variables)]
+            fn combine(&mut self, a: Self::Output, b: Self::Output) -> Self::Output {
+                a
+            }
+        };
+
+        tokens.append_all(match self.flavor {
+            Flavor::Ref => quote! {
+                #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+                pub enum VisitAction {
+                    Continue,
+                    SkipChildren,
+                    Stop,
+                }
+
+                pub trait #trait_name #trait_params #trait_where {
+                    type Output;
+
+                    #arena
+                    #combine
+
+                    /// Called before every node, along with its `enter_*` hook.
+                    /// The stronger of the two actions is taken.
+                    #[allow(unused_variables)]
+                    fn enter_any(&mut self, node: #node_ref) -> VisitAction {
+                        VisitAction::Continue
+                    }
+
+                    /// Called after every node, following its `leave_*` hook.
+                    /// Every node that is entered is left, also once the
+                    /// traversal has stopped.
+                    #[allow(unused_variables)]
+                    fn leave_any(&mut self, node: #node_ref) {}
+
+                    /// Whether a hook has returned `VisitAction::Stop`, after
+                    /// which nothing else is entered, also through `visit_*`
+                    /// overrides. A visitor whose hooks stop keeps this in a
+                    /// field that `stop` sets, and clears it to be reused.
+                    fn stopped(&self) -> bool {
+                        false
+                    }
+
+                    /// Records that a hook has returned `VisitAction::Stop`.
+                    fn stop(&mut self) {
+                        panic!("a hook returned `VisitAction::Stop`, but the visitor does not override `stop` and `stopped` to keep it");
+                    }
+
+                    #func_impl
+                }
+            },
+            Flavor::Mut => quote! {
+                pub trait #trait_name #trait_params #trait_where {
+                    type Output;
+
+                    #arena
+                    #combine
+                    #func_impl
+                }
+            },
+            Flavor::Exhaustive => quote! {
+                pub trait #trait_name #trait_params #trait_where {
+                    type Output;
+
+                    #arena
+                    #func_impl
+                }
+            },
+            Flavor::Try => quote! {
+                pub trait #trait_name #trait_params #trait_where {
+                    type Output;
+                    type Error;
+
+                    #arena
+                    #combine
+                    #func_impl
+                }
+            },
+            Flavor::Ctx => quote! {
+                pub trait #trait_name #trait_params #trait_where {
+                    type Output;
+                    type Ctx;
+
+                    #arena
+                    #combine
+                    #func_impl
+                }
+            },
+            Flavor::Typed => {
+                let output_types = self.output_types();
+
+                quote! {
+                    pub trait #trait_name #trait_params #trait_where {
+                        #arena
+                        #output_types
+                        #func_impl
+                    }
+                }
             }
         });
+        tokens.append_all(walk_impl);
+
+        if self.flavor == Flavor::Ref {
+            tokens.append_all(self.iterative());
+        }
 
         tokens
     }
 
-    pub fn func_impl(&self) -> TokenStream {
-        let mut tokens = TokenStream::default();
+    /// The name of the generated trait, after the configured visitor name.
+    fn trait_name(&self) -> Ident {
+        let visitor = &self.context.visitor;
 
-        let ast_type = NewType::Enum(EnumType {
-            name: Ident::new("Ast", Span::call_site()),
-            variants: self.context.variants.clone(),
-        });
+        match self.flavor {
+            Flavor::Ref => visitor.clone(),
+            Flavor::Mut => format_ident!("{}Mut", visitor),
+            Flavor::Exhaustive => format_ident!("Exhaustive{}", visitor),
+            Flavor::Try => format_ident!("Try{}", visitor),
+            Flavor::Ctx => format_ident!("Ctx{}", visitor),
+            Flavor::Typed => format_ident!("Typed{}", visitor),
+        }
+    }
 
-        let functions: Vec<TokenStream> = self
-            .context
-            .new_types
+    /// The parameters of the trait: those of the nodes, behind `'ast` for
+    /// the traits over shared references.
+    fn trait_generics(&self) -> Generics {
+        if self.mutable() {
+            self.context.cloning_arena(&self.context.generics)
+        } else {
+            outliving(&self.context.generics, "'ast")
+        }
+    }
+
+    /// The parameters of a `walk_*` function: those of the trait and the
+    /// visitor `V` implementing it.
+    fn walk_generics(&self) -> Generics {
+        let trait_name = self.trait_name();
+        let mut generics = self.trait_generics();
+        let (_, trait_args, _) = generics.split_for_impl();
+        let visitor: GenericParam = parse_quote! { V: #trait_name #trait_args };
+
+        generics.params.push(visitor);
+        if self.flavor != Flavor::Typed {
+            generics
+                .make_where_clause()
+                .predicates
+                .push(parse_quote! { V::Output: Default });
+        }
+
+        generics
+    }
+
+    /// `NodeRef<'ast, ..>`, the type of the nodes the hooks receive.
+    fn node_ref(&self) -> TokenStream {
+        let generics = outliving(&self.context.generics, "'ast");
+        let (_, ty_generics, _) = generics.split_for_impl();
+
+        quote! { NodeRef #ty_generics }
+    }
+
+    fn shape(&self, ty: &Type) -> Shape {
+        Shape::resolving(ty, &self.new_idents, &self.context.arena_ids())
+    }
+
+    /// The arena that the ids in the visited nodes are resolved through.
+    fn arena(&self) -> TokenStream {
+        if self.mutable() {
+            quote! { visitor.arena_mut() }
+        } else {
+            quote! { visitor.arena() }
+        }
+    }
+
+    /// `walk_iterative`, which runs the hooks of a `Visitor` in the same order
+    /// as its `walk_*` functions, but from a work stack on the heap.
+    fn iterative(&self) -> TokenStream {
+        let names = self.context.node_names();
+
+        let enter_names = names
             .iter()
-            .map(|nt| self.single_func(nt))
-            .collect();
-        tokens.append_all(self.single_func(&ast_type));
-        tokens.extend(functions);
+            .map(|n| format_ident!("enter_{}", n.to_string().to_lowercase(), span = n.span()));
+        let leave_names = names
+            .iter()
+            .map(|n| format_ident!("leave_{}", n.to_string().to_lowercase(), span = n.span()));
+        let (enter_refs, leave_refs) = (names.iter(), names.iter());
+        let walk_generics = self.walk_generics();
+        let (walk_params, _, walk_where) = walk_generics.split_for_impl();
+        let node_ref = self.node_ref();
+        let arena = if self.context.arena {
+            self.arena()
+        } else {
+            TokenStream::new()
+        };
+
+        quote! {
+            /// Runs the `enter_*` and `leave_*` hooks of `visitor` over `node`
+            /// and everything below it, in the same order as the `walk_*`
+            /// functions and obeying the same `VisitAction`s. After a `Stop`,
+            /// nothing else is entered, and the nodes entered are left.
+            ///
+            /// Pending nodes are kept on the heap instead of the call stack, so
+            /// there is no limit on how deep the tree may be. In exchange, only
+            /// the hooks run: `visit_*` overrides and outputs are not used.
+            pub fn walk_iterative #walk_params(visitor: &mut V, node: #node_ref) #walk_where {
+                enum Work<T> {
+                    Enter(T),
+                    Leave(T),
+                }
+
+                let mut stack = vec![Work::Enter(node)];
+
+                while let Some(work) = stack.pop() {
+                    match work {
+                        Work::Enter(_) if visitor.stopped() => {}
+                        Work::Enter(node) => {
+                            let action = visitor.enter_any(node).max(match node {
+                                #(
+                                    NodeRef::#enter_refs(node) => visitor.#enter_names(node),
+                                )*
+                            });
+
+                            match action {
+                                VisitAction::Continue => {
+                                    let children: Vec<#node_ref> = node.children(#arena).collect();
+                                    stack.push(Work::Leave(node));
+                                    stack.extend(children.into_iter().rev().map(Work::Enter));
+                                }
+                                VisitAction::SkipChildren => stack.push(Work::Leave(node)),
+                                VisitAction::Stop => {
+                                    visitor.stop();
+                                    stack.push(Work::Leave(node));
+                                }
+                            }
+                        }
+                        Work::Leave(node) => {
+                            match node {
+                                #(
+                                    NodeRef::#leave_refs(node) => visitor.#leave_names(node),
+                                )*
+                            }
+                            visitor.leave_any(node);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The `{Name}Output` associated types of `TypedVisitor`, and the hooks
+    /// converting the output of each node an enum or wrapper holds into its
+    /// own. The hooks are required, so no output is dropped unnoticed.
+    fn output_types(&self) -> TokenStream {
+        let mut tokens = TokenStream::default();
+
+        let root_type = self.context.root_type();
+
+        for new_type in root_type.iter().chain(&self.context.new_types) {
+            let inner_types: Vec<&Type> = match new_type {
+                NewType::Enum(e) => e.variants.iter().flat_map(|v| &v.ty).collect(),
+                NewType::WrapperStruct(w) => vec![&w.ty],
+                NewType::Struct(_) => Vec::new(),
+            };
+
+            let mut sources: Vec<String> = Vec::new();
+            for ty in inner_types {
+                if let Shape::Node(node) = Shape::of(ty, &self.new_idents) {
+                    if !sources.contains(&node.to_string()) {
+                        sources.push(node.to_string());
+                    }
+                }
+            }
+
+            let name = new_type.name();
+            let output = self.output_ident(name);
+            tokens.append_all(quote! {
+                type #output;
+            });
+
+            for source in sources {
+                let source = Ident::new(&source, Span::call_site());
+                let hook = self.conversion_ident(name, &source);
+                let source_output = self.output_ident(&source);
+                let doc = format!(
+                    " Converts the output of a `{}` held by a `{}` into the `{}`'s own.",
+                    source, name, name
+                );
+
+                tokens.append_all(quote! {
+                    #[doc = #doc]
+                    fn #hook(&mut self, output: Self::#source_output) -> Self::#output;
+                });
+            }
+        }
 
         tokens
     }
 
-    pub fn single_func(&self, new_type: &NewType) -> TokenStream {
+    /// `{owner}_from_{node}`, the hook of `TypedVisitor` converting the output
+    /// of a `node` into that of the `owner` holding it.
+    fn conversion_ident(&self, owner: &Ident, node: &Ident) -> Ident {
+        format_ident!(
+            "{}_from_{}",
+            owner.to_string().to_lowercase(),
+            node.to_string().to_lowercase(),
+            span = owner.span()
+        )
+    }
+
+    /// `{Name}Output`, the output type of `name` in `TypedVisitor`.
+    fn output_ident(&self, name: &Ident) -> Ident {
+        format_ident!("{}Output", name, span = name.span())
+    }
+
+    fn mutable(&self) -> bool {
+        self.flavor == Flavor::Mut
+    }
+
+    /// `visit_{name}`, suffixed with `_mut` for the mutable visitor.
+    fn visit_ident(&self, name: &str, span: Span) -> Ident {
+        if self.mutable() {
+            format_ident!("visit_{}_mut", name.to_lowercase(), span = span)
+        } else {
+            format_ident!("visit_{}", name.to_lowercase(), span = span)
+        }
+    }
+
+    /// `walk_{name}`, suffixed with `_mut` for the mutable visitor and
+    /// prefixed with `try_`, `ctx_` or `typed_` for the other flavors.
+    fn walk_ident(&self, name: &str, span: Span) -> Ident {
+        match self.flavor {
+            Flavor::Mut => format_ident!("walk_{}_mut", name.to_lowercase(), span = span),
+            Flavor::Try => format_ident!("try_walk_{}", name.to_lowercase(), span = span),
+            Flavor::Ctx => format_ident!("ctx_walk_{}", name.to_lowercase(), span = span),
+            Flavor::Typed => format_ident!("typed_walk_{}", name.to_lowercase(), span = span),
+            _ => format_ident!("walk_{}", name.to_lowercase(), span = span),
+        }
+    }
+
+    /// The reference type a visit method receives a node through.
+    fn reference<T: ToTokens>(&self, ty: T) -> TokenStream {
+        if self.mutable() {
+            quote! { &mut #ty }
+        } else {
+            quote! { &'ast #ty }
+        }
+    }
+
+    /// The return type of a visit method of `owner`'s on `visitor`, either
+    /// `Self` or `V`.
+    fn output(&self, visitor: TokenStream, owner: &Ident) -> TokenStream {
+        match self.flavor {
+            Flavor::Try => quote! { Result<#visitor::Output, #visitor::Error> },
+            Flavor::Typed => {
+                let output = self.output_ident(owner);
+                quote! { #visitor::#output }
+            }
+            _ => quote! { #visitor::Output },
+        }
+    }
+
+    /// The default output of a visit method on `visitor`.
+    fn default_output(&self, visitor: TokenStream) -> TokenStream {
+        self.ok(quote! { #visitor::Output::default() })
+    }
+
+    /// Whether the methods that would return the default output are required
+    /// instead, so that the outputs need no `Default`.
+    fn required(&self) -> bool {
+        matches!(self.flavor, Flavor::Exhaustive | Flavor::Typed)
+    }
+
+    /// Converts the output of a node visited by `owner`'s default body.
+    fn convert(&self, owner: &Ident, node: &Ident, expr: TokenStream) -> TokenStream {
+        if self.flavor == Flavor::Typed {
+            let hook = self.conversion_ident(owner, node);
+            quote! {{
+                let output = #expr;
+                visitor.#hook(output)
+            }}
+        } else {
+            expr
+        }
+    }
+
+    /// The trailing `ctx` parameter of a visit method on `visitor`.
+    fn ctx_param(&self, visitor: TokenStream) -> TokenStream {
+        if self.flavor == Flavor::Ctx {
+            quote! { , ctx: &mut #visitor::Ctx }
+        } else {
+            TokenStream::new()
+        }
+    }
+
+    /// The trailing `ctx` argument of a visit method call.
+    fn ctx_arg(&self) -> TokenStream {
+        if self.flavor == Flavor::Ctx {
+            quote! { , ctx }
+        } else {
+            TokenStream::new()
+        }
+    }
+
+    /// Returns the plain output `expr` from a visit method.
+    fn ok(&self, expr: TokenStream) -> TokenStream {
+        if self.flavor == Flavor::Try {
+            quote! { Ok(#expr) }
+        } else {
+            expr
+        }
+    }
+
+    /// Returns the trait methods and the `walk_*` functions they delegate to.
+    pub fn func_impl(&self) -> (TokenStream, TokenStream) {
+        let mut funcs = TokenStream::default();
+        let mut walks = TokenStream::default();
+
+        let root_type = self.context.root_type();
+
+        for new_type in root_type.iter().chain(&self.context.new_types) {
+            let (func, walk) = self.single_func(new_type);
+            funcs.extend(func);
+            walks.extend(walk);
+        }
+
+        (funcs, walks)
+    }
+
+    /// Statements visiting every node reachable through the reference `expr`
+    /// and combining each result into `output`.
+    fn walk(&self, ty: &Type, expr: TokenStream) -> TokenStream {
+        let question = if self.flavor == Flavor::Try {
+            quote! { ? }
+        } else {
+            TokenStream::new()
+        };
+
+        let ctx = self.ctx_arg();
+
+        self.shape(ty)
+            .visit(expr, self.mutable(), &self.arena(), &|node, expr| {
+                let visit = self.visit_ident(&node.to_string(), node.span());
+
+                let visit = quote! {
+                    let child = visitor.#visit(#expr #ctx)#question;
+                    output = visitor.combine(output, child);
+                };
+
+                // Once stopped, the remaining children are left out
+                if self.flavor == Flavor::Ref {
+                    quote! {
+                        if !visitor.stopped() {
+                            #visit
+                        }
+                    }
+                } else {
+                    visit
+                }
+            })
+    }
+
+    /// The body of a `walk_*` function that combines the children `walks`.
+    fn combined(&self, walks: TokenStream) -> TokenStream {
+        let output = self.ok(quote! { output });
+
+        quote! {
+            let mut output = V::Output::default();
+            #walks
+            #output
+        }
+    }
+
+    /// A trait method `visit_{name}` whose default body is `walk_{name}`,
+    /// along with the `walk_{name}` function itself. Without a `body` there
+    /// is nothing to walk, and the method just returns the default output.
+    ///
+    /// The exhaustive visitor has no `walk_*` functions: a `body` is inlined
+    /// into the method, and without one the method is required, as it is in
+    /// the typed visitor.
+    ///
+    /// In `Visitor`, the `walk_*` function of a `node` type always exists and
+    /// runs the `enter_*` and `leave_*` hooks around its body.
+    ///
+    /// The methods take a reference to each of `args`, a name and a type,
+    /// which is only ever more than one for a variant with several fields.
+    fn delegate(
+        &self,
+        name: &Ident,
+        args: &[(Ident, TokenStream)],
+        body: Option<TokenStream>,
+        node: bool,
+        owner: &Ident,
+    ) -> (TokenStream, TokenStream) {
+        let (name, span) = (&name.to_string(), name.span());
+        let visit_name = self.visit_ident(name, span);
+        let walk_name = self.walk_ident(name, span);
+        let arg: Vec<&Ident> = args.iter().map(|(arg, _)| arg).collect();
+        let reference: Vec<TokenStream> = args.iter().map(|(_, ty)| self.reference(ty)).collect();
+        let output = self.output(quote! { Self }, owner);
+        let ctx_param = self.ctx_param(quote! { Self });
+        let ctx_arg = self.ctx_arg();
+
+        // Not every argument has nodes to walk, and each is taken by a
+        // reference to its declared type, such as `&Vec<T>`
+        let allow = quote! { #[allow(unused_variables, clippy::ptr_arg)] };
+
+        if node && self.flavor == Flavor::Ref {
+            return self.hooked(name, span, arg[0], reference[0].clone(), body);
+        }
+
+        if self.flavor == Flavor::Exhaustive {
+            let func = match body {
+                Some(body) => quote! {
+                    #allow
+                    fn #visit_name(&mut self, #(#arg: #reference),*) -> #output where Self: Sized  {
+                        let visitor = self;
+                        #body
+                    }
+                },
+                None => quote! {
+                    #allow
+                    fn #visit_name(&mut self, #(#arg: #reference),*) -> #output where Self: Sized;
+                },
+            };
+
+            return (func, TokenStream::new());
+        }
+
+        let body = match body {
+            Some(body) => body,
+            None if self.required() => {
+                let func = quote! {
+                    #allow
+                    fn #visit_name(&mut self, #(#arg: #reference),* #ctx_param) -> #output where Self: Sized;
+                };
+
+                return (func, TokenStream::new());
+            }
+            None => {
+                let default = self.default_output(quote! { Self });
+                let func = quote! {
+                    #allow
+                    fn #visit_name(&mut self, #(#arg: #reference),* #ctx_param) -> #output where Self: Sized  {
+                        #default
+                    }
+                };
+
+                return (func, TokenStream::new());
+            }
+        };
+
+        let walk_generics = self.walk_generics();
+        let (walk_params, _, walk_where) = walk_generics.split_for_impl();
+        let walk_output = self.output(quote! { V }, owner);
+        let walk_ctx_param = self.ctx_param(quote! { V });
+
+        let func = quote! {
+            #allow
+            fn #visit_name(&mut self, #(#arg: #reference),* #ctx_param) -> #output where Self: Sized  {
+                #walk_name(self, #(#arg),* #ctx_arg)
+            }
+        };
+
+        let walk = quote! {
+            #allow
+            pub fn #walk_name #walk_params(visitor: &mut V, #(#arg: #reference),* #walk_ctx_param) -> #walk_output #walk_where {
+                #body
+            }
+        };
+
+        (func, walk)
+    }
+
+    /// The `enter_*`, `leave_*` and `visit_*` methods of a node type, and its
+    /// `walk_*` function running the hooks around `body`.
+    fn hooked(
+        &self,
+        name: &str,
+        span: Span,
+        arg: &Ident,
+        reference: TokenStream,
+        body: Option<TokenStream>,
+    ) -> (TokenStream, TokenStream) {
+        let visit_name = self.visit_ident(name, span);
+        let walk_name = self.walk_ident(name, span);
+        let enter_name = format_ident!("enter_{}", name.to_lowercase(), span = span);
+        let leave_name = format_ident!("leave_{}", name.to_lowercase(), span = span);
+
+        let body = body.unwrap_or_else(|| quote! { V::Output::default() });
+
+        let func = quote! {
+            #[allow(unused_variables)]
+            fn #enter_name(&mut self, #arg: #reference) -> VisitAction {
+                VisitAction::Continue
+            }
+
+            #[allow(unused_variables)]
+            fn #leave_name(&mut self, #arg: #reference) {}
+
+            fn #visit_name(&mut self, #arg: #reference) -> Self::Output where Self: Sized  {
+                #walk_name(self, #arg)
+            }
+        };
+
+        let walk_generics = self.walk_generics();
+        let (walk_params, _, walk_where) = walk_generics.split_for_impl();
+        let walk = quote! {
+            pub fn #walk_name #walk_params(visitor: &mut V, #arg: #reference) -> V::Output #walk_where {
+                if visitor.stopped() {
+                    return V::Output::default();
+                }
+                let action = visitor.enter_any(NodeRef::from(#arg)).max(visitor.#enter_name(#arg));
+
+                let output = match action {
+                    VisitAction::Continue => { #body },
+                    VisitAction::SkipChildren => V::Output::default(),
+                    VisitAction::Stop => {
+                        visitor.stop();
+                        V::Output::default()
+                    }
+                };
+
+                visitor.#leave_name(#arg);
+                visitor.leave_any(NodeRef::from(#arg));
+
+                output
+            }
+        };
+
+        (func, walk)
+    }
+
+    pub fn single_func(&self, new_type: &NewType) -> (TokenStream, TokenStream) {
         let mut tokens = TokenStream::default();
+        let mut walks = TokenStream::default();
+
+        let exhaustive = self.flavor == Flavor::Exhaustive;
+        let required = self.required();
 
         match new_type {
             NewType::Enum(e) => {
                 let name = &e.name;
-                let name_lower =
-                    format_ident!("{}", name.to_string().to_lowercase(), span = name.span());
-                let visit_name = format_ident!(
-                    "visit_{}",
-                    name.to_string().to_lowercase(),
-                    span = name.span()
-                );
+                let name_lower = lowercase(name);
 
-                // let variants = &e.variants;
+                let ctx = self.ctx_arg();
 
                 // Raw variants will be: visit_name_rawname()
-                let raw_variants = e.variants.iter().filter(|v| v.ty.is_none());
+                let raw_variants = e.variants.iter().filter(|v| v.is_unit());
+
+                // Variants with several or named fields will be:
+                // visit_name_variantname(field, ..)
+                let compound_variants: Vec<_> =
+                    e.variants.iter().filter(|v| v.fields.is_some()).collect();
+                let compound_cfgs = compound_variants.iter().map(|v| v.cfg());
+                let compound_patterns = compound_variants.iter().map(|v| v.pattern(name));
+                let compound_calls = compound_variants.iter().map(|v| {
+                    let visit = self.visit_ident(&format!("{}_{}", name, v.name), v.name.span());
+                    let bindings = v.bindings().into_iter().map(|(binding, _)| binding);
+
+                    quote! { visitor.#visit(#(#bindings),* #ctx) }
+                });
 
                 // New Type variants will be: visit_newtype(newtype)
                 // Basic Type variants will be: visit_name_variantname(basic_type);
                 let (new_type_variants, basic_type_variants): (Vec<_>, Vec<_>) =
                     e.variants.iter().filter(|v| v.ty.is_some()).partition(|v| {
-                        let type_string =
-                            v.ty.as_ref()
-                                .map(|t| {
-                                    let mut tokens = TokenStream::default();
-                                    t.to_tokens(&mut tokens);
-                                    tokens.to_string()
-                                })
-                                .unwrap_or_default();
-
-                        self.new_idents.contains(&type_string)
+                        matches!(
+                            v.ty.as_ref().map(|t| Shape::of(t, &self.new_idents)),
+                            Some(Shape::Node(_))
+                        )
                     });
 
                 let raw_idents = raw_variants.clone().map(|v| &v.name);
+                let raw_cfgs = raw_variants.clone().map(|v| v.cfg());
                 let new_type_idents = new_type_variants.iter().map(|v| &v.name);
-                let new_type_types = new_type_variants.iter().map(|v| &v.ty).flatten();
+                let new_type_cfgs = new_type_variants.iter().map(|v| v.cfg());
+                let new_type_types = new_type_variants.iter().flat_map(|v| &v.ty);
                 let basic_idents = basic_type_variants.iter().map(|v| &v.name);
-                let basic_types = basic_type_variants.iter().map(|v| &v.ty).flatten();
-
-                let raw_visit = raw_idents.clone().map(|i| {
-                    format_ident!(
-                        "visit_{}_{}",
-                        name.to_string().to_lowercase(),
-                        i.to_string().to_lowercase(),
-                        span = i.span()
-                    )
-                });
-                let new_type_visit = new_type_types.clone().map(|ty| {
-                    let mut tokens = TokenStream::new();
-                    ty.to_tokens(&mut tokens);
-                    format_ident!("visit_{}", tokens.to_string().to_lowercase())
-                });
-                let basic_visit = basic_idents.clone().map(|i| {
-                    format_ident!(
-                        "visit_{}_{}",
-                        name.to_string().to_lowercase(),
-                        i.to_string().to_lowercase(),
-                        span = i.span()
-                    )
-                });
-                let basic_visit_func = basic_visit.clone();
-                let basic_types_func = basic_types.clone();
+                let basic_cfgs = basic_type_variants.iter().map(|v| v.cfg());
+
+                let raw_visit = raw_idents
+                    .clone()
+                    .map(|i| self.visit_ident(&format!("{}_{}", name, i), i.span()));
+                let new_type_nodes: Vec<Ident> = new_type_types
+                    .map(|ty| match Shape::of(ty, &self.new_idents) {
+                        Shape::Node(node) => node,
+                        _ => unreachable!(),
+                    })
+                    .collect();
+                let basic_visit = basic_idents
+                    .clone()
+                    .map(|i| self.visit_ident(&format!("{}_{}", name, i), i.span()));
 
                 let raw_visit_func = raw_visit.clone();
+                let new_type_ctx = new_type_variants.iter().map(|_| &ctx);
+                let basic_ctx = basic_type_variants.iter().map(|_| &ctx);
+                let raw_ctx = if self.flavor == Flavor::Ctx {
+                    quote! { ctx }
+                } else {
+                    TokenStream::new()
+                };
+                let raw_ctx_repeat = raw_visit.clone().map(|_| &raw_ctx);
+                let new_type_calls = new_type_nodes.iter().zip(new_type_ctx).map(|(node, ctx)| {
+                    let visit = self.visit_ident(&node.to_string(), Span::call_site());
+                    self.convert(name, node, quote! { visitor.#visit(v #ctx) })
+                });
 
-                tokens.append_all(quote! {
-                    fn #visit_name(&mut self, #name_lower: &'ast #name) -> Self::Output where Self: Sized  {
+                let ty = self.context.ty(name);
+                let (func, walk) = self.delegate(
+                    name,
+                    &[(name_lower.clone(), ty)],
+                    Some(quote! {
                         match #name_lower {
                             #(
-                                #name::#new_type_idents(v) => self.#new_type_visit(&v),
+                                #new_type_cfgs
+                                #name::#new_type_idents(v) => #new_type_calls,
+                            )*
+                            #(
+                                #basic_cfgs
+                                #name::#basic_idents(v) => visitor.#basic_visit(v #basic_ctx),
                             )*
                             #(
-                                #name::#basic_idents(v) => self.#basic_visit(&v),
+                                #compound_cfgs
+                                #compound_patterns => #compound_calls,
                             )*
                             #(
-                                #name::#raw_idents => self.#raw_visit(),
+                                #raw_cfgs
+                                #name::#raw_idents => visitor.#raw_visit(#raw_ctx_repeat),
                             )*
                         }
-                    }
+                    }),
+                    true,
+                    name,
+                );
+                tokens.append_all(func);
+                walks.append_all(walk);
 
-                    #(
-                        fn #basic_visit_func(&mut self, v: &'ast #basic_types_func) -> Self::Output where Self: Sized  {
-                            Self::Output::default()
-                        }
-                    )*
+                for variant in basic_type_variants {
+                    let ident = &variant.name;
+                    let ty = variant.ty.as_ref().unwrap();
+                    let v = Ident::new("v", Span::call_site());
 
-                    #(
-                        fn #raw_visit_func(&mut self) -> Self::Output where Self: Sized  {
-                            Self::Output::default()
-                        }
-                    )*
-                });
+                    let walk = self.walk(ty, quote! { v });
+                    let body = if walk.is_empty() || required {
+                        None
+                    } else {
+                        Some(self.combined(walk))
+                    };
+
+                    let (func, walk) = self.delegate(
+                        &format_ident!("{}_{}", name, ident, span = ident.span()),
+                        &[(v, ty.to_token_stream())],
+                        body,
+                        false,
+                        name,
+                    );
+                    tokens.append_all(func);
+                    walks.append_all(walk);
+                }
+
+                for variant in compound_variants {
+                    let ident = &variant.name;
+                    let bindings = variant.bindings();
+
+                    let field_walks: Vec<TokenStream> = bindings
+                        .iter()
+                        .map(|(binding, ty)| self.walk(ty, quote! { #binding }))
+                        .collect();
+                    let body = if field_walks.iter().all(|walk| walk.is_empty()) || required {
+                        None
+                    } else {
+                        Some(self.combined(quote! { #(#field_walks)* }))
+                    };
+
+                    let args: Vec<(Ident, TokenStream)> = bindings
+                        .into_iter()
+                        .map(|(binding, ty)| (binding, ty.to_token_stream()))
+                        .collect();
+                    let (func, walk) = self.delegate(
+                        &format_ident!("{}_{}", name, ident, span = ident.span()),
+                        &args,
+                        body,
+                        false,
+                        name,
+                    );
+                    tokens.append_all(func);
+                    walks.append_all(walk);
+                }
+
+                let output = self.output(quote! { Self }, name);
+                if required {
+                    tokens.append_all(quote! {
+                        #(
+                            fn #raw_visit_func(&mut self) -> #output where Self: Sized;
+                        )*
+                    });
+                } else {
+                    let default = self.default_output(quote! { Self });
+                    let ctx_param = self.ctx_param(quote! { Self });
+                    tokens.append_all(quote! {
+                        #(
+                            #[allow(unused_variables)]
+                            fn #raw_visit_func(&mut self #ctx_param) -> #output where Self: Sized  {
+                                #default
+                            }
+                        )*
+                    });
+                }
             }
             NewType::Struct(s) => {
                 let name = &s.name;
-                let name_lower =
-                    format_ident!("{}", name.to_string().to_lowercase(), span = name.span());
-                let visit_name = format_ident!(
-                    "visit_{}",
-                    name.to_string().to_lowercase(),
-                    span = name.span()
-                );
+                let name_lower = lowercase(name);
 
-                let (new_type_field_names, new_type_visit): (Vec<_>, Vec<_>) = s
+                let field_ref = if self.mutable() {
+                    quote! { &mut }
+                } else {
+                    quote! { & }
+                };
+                let field_walks: Vec<TokenStream> = s
                     .fields
                     .iter()
-                    .filter_map(|f| {
-                        let mut tokens = TokenStream::new();
-                        f.ty.to_tokens(&mut tokens);
-
-                        if self.new_idents.contains(&tokens.to_string()) {
-                            Some((
-                                &f.ident,
-                                format_ident!("visit_{}", tokens.to_string().to_lowercase()),
-                            ))
+                    .map(|f| {
+                        let ident = &f.ident;
+                        let cfg = f.cfg();
+                        let walk = self.walk(&f.ty, quote! { #field_ref #name_lower.#ident });
+
+                        if cfg.is_empty() || walk.is_empty() {
+                            walk
                         } else {
-                            None
+                            quote! { #cfg { #walk } }
                         }
                     })
-                    .unzip();
+                    .filter(|walk| !walk.is_empty())
+                    .collect();
 
-                let _name_lower_func = name_lower.clone();
-                let name_lower_repeat = (0..new_type_field_names.len()).map(|_| &name_lower);
+                let body = if field_walks.is_empty() || required {
+                    None
+                } else {
+                    Some(self.combined(quote! { #(#field_walks)* }))
+                };
 
-                tokens.append_all(quote! {
-                    fn #visit_name(&mut self, #name_lower: &'ast #name) -> Self::Output where Self: Sized  {
-                        #(
-                            self.#new_type_visit(&#name_lower_repeat.#new_type_field_names);
-                        )*
-                        Self::Output::default()
-                    }
-                });
+                let ty = self.context.ty(name);
+                let (func, walk) = self.delegate(name, &[(name_lower, ty)], body, true, name);
+                tokens.append_all(func);
+                walks.append_all(walk);
             }
             NewType::WrapperStruct(s) => {
                 let name = &s.name;
-                let name_lower =
-                    format_ident!("{}", name.to_string().to_lowercase(), span = name.span());
-                let visit_name = format_ident!(
-                    "visit_{}",
-                    name.to_string().to_lowercase(),
-                    span = name.span()
-                );
+                let name_lower = lowercase(name);
 
-                let type_string = {
-                    let mut tokens = TokenStream::new();
-                    s.ty.to_tokens(&mut tokens);
-                    tokens.to_string()
-                };
-
-                let name_lower_inner = name_lower.clone();
-                let inner_call = if self.new_idents.contains(&type_string) {
-                    let visit_new_type =
-                        format_ident!("visit_{}", type_string.to_lowercase(), span = s.ty.span());
-                    quote! { self.#visit_new_type(#name_lower_inner.inner()) }
+                let inner = if self.mutable() {
+                    quote! { #name_lower.inner_mut() }
                 } else {
-                    quote! { Self::Output::default() }
+                    quote! { #name_lower.inner() }
                 };
-
-                tokens.append_all(quote! {
-                    fn #visit_name(&mut self, #name_lower: &#name) -> Self::Output where Self: Sized  {
-                        #inner_call
+                let ctx = self.ctx_arg();
+                let inner_call = match self.shape(&s.ty) {
+                    Shape::Node(node) => {
+                        let visit_new_type = self.visit_ident(&node.to_string(), s.ty.span());
+                        Some(self.convert(
+                            name,
+                            &node,
+                            quote! { visitor.#visit_new_type(#inner #ctx) },
+                        ))
                     }
-                });
+                    Shape::Id(node) if exhaustive => {
+                        let visit_new_type = self.visit_ident(&node.to_string(), s.ty.span());
+                        Some(quote! { visitor.#visit_new_type(visitor.arena().get(*#inner)) })
+                    }
+                    Shape::Leaf => None,
+                    _ if required => None,
+                    _ => Some(self.combined(self.walk(&s.ty, inner))),
+                };
+
+                let ty = self.context.ty(name);
+                let (func, walk) = self.delegate(name, &[(name_lower, ty)], inner_call, true, name);
+                tokens.append_all(func);
+                walks.append_all(walk);
             }
         }
 
-        tokens
+        (tokens, walks)
     }
 }
 
@@ -249,7 +1020,7 @@ mod tests {
             },
             E: enum E {
                 Alpha(isize), // Basic type
-                Beta(Ident),  // New Type
+                Beta(Lit),    // New Type
                 Gamma,        // Raw Type
             }
         };
@@ -258,5 +1029,40 @@ mod tests {
         let impl_tokens = visitor.create_visitor();
 
         println!("{}", impl_tokens);
+
+        let visitor_mut = Visitor::new_mut(&context);
+        let impl_tokens = visitor_mut.create_visitor();
+
+        println!("{}", impl_tokens);
+
+        let visitor_exhaustive = Visitor::new_exhaustive(&context);
+        let impl_tokens = visitor_exhaustive.create_visitor();
+
+        println!("{}", impl_tokens);
+
+        let visitor_try = Visitor::new_try(&context);
+        let impl_tokens = visitor_try.create_visitor();
+
+        println!("{}", impl_tokens);
+
+        let visitor_ctx = Visitor::new_ctx(&context);
+        let impl_tokens = visitor_ctx.create_visitor();
+
+        println!("{}", impl_tokens);
+
+        let visitor_typed = Visitor::new_typed(&context);
+        let impl_tokens = visitor_typed.create_visitor();
+
+        println!("{}", impl_tokens);
+
+        // No output is made up: conversions and leaves are left to the visitor
+        let typed = impl_tokens.to_string();
+        assert!(!typed.contains("Default"));
+        assert!(typed.contains(
+            "fn expr_from_lit ( & mut self , output : Self :: LitOutput ) -> Self :: ExprOutput ;"
+        ));
+        assert!(typed.contains(
+            "fn visit_binop ( & mut self , binop : & 'ast Binop ) -> Self :: BinopOutput where Self : Sized ;"
+        ));
     }
 }
@@ -1,7 +1,14 @@
 // #![feature(proc_macro_hygiene)]
 // #![feature(trace_macros)]
 
-pub use asterix_impl::ast;
+pub use asterix_impl::{ast, ast_module};
+
+#[cfg(feature = "serde")]
+#[doc(hidden)]
+pub use serde;
+
+// Generated code names this crate as `::asterix`, including in its own tests
+extern crate self as asterix;
 
 // /*
 // ast!(
@@ -56,7 +63,7 @@ pub use asterix_impl::ast;
 //             rhs: Option<Expr>,
 //         },
 //         Print(Option<Expr>),
-//         Ret |Option<Expr>|, // TODO: Fix From impl
+//         Ret |Option<Expr>|,
 //     },
 //     Decl: enum Decl {
 //         |Stmt|,
@@ -131,4 +138,1661 @@ mod tests {
         let result = interpreter.visit_ast(&Ast::expr(minus_two));
         println!("(1 + 1) - 2: {}", result);
     }
+
+    mod statements {
+        use crate::ast;
+
+        ast!(
+            Lit |isize|,
+            Expr: enum Expr {
+                BinOp: struct BinOp {
+                    op: enum Op {
+                        Plus,
+                        Minus,
+                        Times,
+                        Divide,
+                    },
+                    lhs: Box<Expr>,
+                    rhs: Box<Expr>,
+                },
+                |Lit|
+            },
+            Stmt: enum Stmt {
+                |Expr|,
+                Print(Option<Expr>),
+            },
+            Program: struct Program {
+                stmts: Vec<Stmt>,
+            }
+        );
+
+        use ast::*;
+
+        pub struct Interpreter;
+
+        impl<'ast> Visitor<'ast> for Interpreter {
+            type Output = isize;
+
+            fn visit_binop(&mut self, b: &BinOp) -> isize {
+                let lhs = self.visit_expr(b.lhs());
+                let rhs = self.visit_expr(b.rhs());
+
+                match b.op() {
+                    Op::Plus => lhs + rhs,
+                    Op::Minus => lhs - rhs,
+                    Op::Times => lhs * rhs,
+                    Op::Divide => lhs / rhs,
+                }
+            }
+
+            fn visit_lit(&mut self, lit: &Lit) -> isize {
+                *lit.inner()
+            }
+        }
+
+        /// Counts literals using only the default traversal.
+        pub struct LitCounter;
+
+        impl<'ast> Visitor<'ast> for LitCounter {
+            type Output = usize;
+
+            fn combine(&mut self, a: usize, b: usize) -> usize {
+                a + b
+            }
+
+            fn visit_lit(&mut self, _lit: &Lit) -> usize {
+                1
+            }
+        }
+
+        /// Counts operators, then resumes the default traversal.
+        pub struct OpCounter(usize);
+
+        impl<'ast> Visitor<'ast> for OpCounter {
+            type Output = ();
+
+            fn visit_binop(&mut self, b: &'ast BinOp) {
+                self.0 += 1;
+                walk_binop(self, b)
+            }
+        }
+
+        /// Renders the tree, handling every node kind explicitly.
+        pub struct Printer;
+
+        impl<'ast> ExhaustiveVisitor<'ast> for Printer {
+            type Output = String;
+
+            fn visit_lit(&mut self, lit: &Lit) -> String {
+                lit.inner().to_string()
+            }
+
+            fn visit_binop(&mut self, b: &BinOp) -> String {
+                let op = self.visit_op(b.op());
+                format!(
+                    "({} {} {})",
+                    self.visit_expr(b.lhs()),
+                    op,
+                    self.visit_expr(b.rhs())
+                )
+            }
+
+            fn visit_op_plus(&mut self) -> String {
+                "+".into()
+            }
+
+            fn visit_op_minus(&mut self) -> String {
+                "-".into()
+            }
+
+            fn visit_op_times(&mut self) -> String {
+                "*".into()
+            }
+
+            fn visit_op_divide(&mut self) -> String {
+                "/".into()
+            }
+
+            fn visit_stmt_print(&mut self, v: &Option<Expr>) -> String {
+                match v {
+                    Some(e) => format!("print {};", self.visit_expr(e)),
+                    None => "print;".into(),
+                }
+            }
+
+            fn visit_program(&mut self, p: &Program) -> String {
+                let stmts: Vec<String> = p.stmts().iter().map(|s| self.visit_stmt(s)).collect();
+                stmts.join("\n")
+            }
+        }
+
+        /// Evaluates expressions, stopping at the first division by zero.
+        pub struct CheckedInterpreter {
+            lits: usize,
+        }
+
+        impl<'ast> TryVisitor<'ast> for CheckedInterpreter {
+            type Output = isize;
+            type Error = String;
+
+            fn visit_lit(&mut self, lit: &Lit) -> Result<isize, String> {
+                self.lits += 1;
+                Ok(*lit.inner())
+            }
+
+            fn visit_binop(&mut self, b: &'ast BinOp) -> Result<isize, String> {
+                let lhs = self.visit_expr(b.lhs())?;
+                let rhs = self.visit_expr(b.rhs())?;
+
+                match b.op() {
+                    Op::Plus => Ok(lhs + rhs),
+                    Op::Minus => Ok(lhs - rhs),
+                    Op::Times => Ok(lhs * rhs),
+                    Op::Divide if rhs == 0 => Err("division by zero".into()),
+                    Op::Divide => Ok(lhs / rhs),
+                }
+            }
+        }
+
+        /// Tracks nesting depth using only the hooks.
+        pub struct Depth {
+            depth: usize,
+            max: usize,
+        }
+
+        impl<'ast> Visitor<'ast> for Depth {
+            type Output = ();
+
+            fn enter_any(&mut self, _node: NodeRef<'ast>) -> VisitAction {
+                self.depth += 1;
+                self.max = self.max.max(self.depth);
+                VisitAction::Continue
+            }
+
+            fn leave_any(&mut self, _node: NodeRef<'ast>) {
+                self.depth -= 1;
+            }
+        }
+
+        /// Records the first literal, skipping the operands of `/`.
+        pub struct FirstLit {
+            entered: usize,
+            found: Option<isize>,
+            stopped: bool,
+        }
+
+        impl<'ast> Visitor<'ast> for FirstLit {
+            type Output = ();
+
+            fn stopped(&self) -> bool {
+                self.stopped
+            }
+
+            fn stop(&mut self) {
+                self.stopped = true;
+            }
+
+            fn enter_binop(&mut self, b: &'ast BinOp) -> VisitAction {
+                match b.op() {
+                    Op::Divide => VisitAction::SkipChildren,
+                    _ => VisitAction::Continue,
+                }
+            }
+
+            fn enter_lit(&mut self, lit: &'ast Lit) -> VisitAction {
+                self.entered += 1;
+                self.found = Some(*lit.inner());
+                VisitAction::Stop
+            }
+        }
+
+        /// Records every hook in the order it runs, stopping at the first
+        /// literal if `stop` is set.
+        #[derive(Default)]
+        pub struct Trace<'ast> {
+            events: Vec<(bool, std::mem::Discriminant<NodeRef<'ast>>)>,
+            stop: bool,
+            stopped: bool,
+        }
+
+        impl<'ast> Trace<'ast> {
+            fn stopping() -> Self {
+                Trace {
+                    stop: true,
+                    ..Trace::default()
+                }
+            }
+
+            /// Whether every node entered was left, after its children.
+            fn balanced(&self) -> bool {
+                let mut open = Vec::new();
+                for (enter, node) in &self.events {
+                    match enter {
+                        true => open.push(node),
+                        false if open.pop() != Some(node) => return false,
+                        false => {}
+                    }
+                }
+                open.is_empty()
+            }
+        }
+
+        impl<'ast> Visitor<'ast> for Trace<'ast> {
+            type Output = ();
+
+            fn stopped(&self) -> bool {
+                self.stopped
+            }
+
+            fn stop(&mut self) {
+                self.stopped = true;
+            }
+
+            fn enter_any(&mut self, node: NodeRef<'ast>) -> VisitAction {
+                self.events.push((true, std::mem::discriminant(&node)));
+                match node {
+                    NodeRef::Lit(_) if self.stop => VisitAction::Stop,
+                    _ => VisitAction::Continue,
+                }
+            }
+
+            fn leave_any(&mut self, node: NodeRef<'ast>) {
+                self.events.push((false, std::mem::discriminant(&node)));
+            }
+        }
+
+        /// Records the literals it enters, stopping at the first, while
+        /// visiting each statement itself.
+        #[derive(Default)]
+        pub struct StopAtLit {
+            lits: Vec<isize>,
+            stopped: bool,
+        }
+
+        impl<'ast> Visitor<'ast> for StopAtLit {
+            type Output = ();
+
+            fn stopped(&self) -> bool {
+                self.stopped
+            }
+
+            fn stop(&mut self) {
+                self.stopped = true;
+            }
+
+            fn enter_lit(&mut self, lit: &'ast Lit) -> VisitAction {
+                self.lits.push(*lit.inner());
+                VisitAction::Stop
+            }
+
+            fn visit_program(&mut self, p: &'ast Program) {
+                for s in p.stmts() {
+                    self.visit_stmt(s);
+                }
+            }
+        }
+
+        /// Records each literal with the number of operators above it.
+        pub struct LitDepths(Vec<(isize, usize)>);
+
+        impl<'ast> CtxVisitor<'ast> for LitDepths {
+            type Output = ();
+            type Ctx = usize;
+
+            fn visit_binop(&mut self, b: &'ast BinOp, depth: &mut usize) {
+                *depth += 1;
+                ctx_walk_binop(self, b, depth);
+                *depth -= 1;
+            }
+
+            fn visit_lit(&mut self, lit: &Lit, depth: &mut usize) {
+                self.0.push((*lit.inner(), *depth));
+            }
+        }
+
+        /// Evaluates expressions to values and statements to `()`.
+        pub struct Evaluator {
+            printed: Vec<i64>,
+        }
+
+        impl<'ast> TypedVisitor<'ast> for Evaluator {
+            type AstOutput = ();
+            type LitOutput = i64;
+            type ExprOutput = i64;
+            type BinOpOutput = i64;
+            type OpOutput = ();
+            type StmtOutput = ();
+            type ProgramOutput = ();
+
+            fn ast_from_lit(&mut self, _: i64) {}
+
+            fn ast_from_expr(&mut self, _: i64) {}
+
+            fn ast_from_stmt(&mut self, _: ()) {}
+
+            fn ast_from_program(&mut self, _: ()) {}
+
+            fn expr_from_lit(&mut self, value: i64) -> i64 {
+                value
+            }
+
+            fn expr_from_binop(&mut self, value: i64) -> i64 {
+                value
+            }
+
+            // The value of an expression statement is dropped
+            fn stmt_from_expr(&mut self, _: i64) {}
+
+            fn visit_op_plus(&mut self) {}
+
+            fn visit_op_minus(&mut self) {}
+
+            fn visit_op_times(&mut self) {}
+
+            fn visit_op_divide(&mut self) {}
+
+            fn visit_lit(&mut self, lit: &Lit) -> i64 {
+                *lit.inner() as i64
+            }
+
+            fn visit_binop(&mut self, b: &'ast BinOp) -> i64 {
+                let (lhs, rhs) = (self.visit_expr(b.lhs()), self.visit_expr(b.rhs()));
+
+                match b.op() {
+                    Op::Plus => lhs + rhs,
+                    Op::Minus => lhs - rhs,
+                    Op::Times => lhs * rhs,
+                    Op::Divide => lhs / rhs,
+                }
+            }
+
+            fn visit_stmt_print(&mut self, v: &'ast Option<Expr>) {
+                if let Some(expr) = v {
+                    let value = self.visit_expr(expr);
+                    self.printed.push(value);
+                }
+            }
+
+            fn visit_program(&mut self, program: &'ast Program) {
+                for stmt in program.stmts() {
+                    self.visit_stmt(stmt);
+                }
+            }
+        }
+
+        /// Rewrites every `+` into a `-` in place.
+        pub struct Negate;
+
+        impl VisitorMut for Negate {
+            type Output = ();
+
+            fn visit_binop_mut(&mut self, b: &mut BinOp) {
+                if let Op::Plus = b.op() {
+                    *b.op_mut() = Op::Minus;
+                }
+
+                self.visit_expr_mut(b.lhs_mut());
+                self.visit_expr_mut(b.rhs_mut());
+            }
+        }
+
+        /// Replaces every `BinOp` over two literals with its value.
+        pub struct ConstFold;
+
+        impl Fold for ConstFold {
+            fn fold_expr(&mut self, expr: Expr) -> Expr {
+                match fold_expr(self, expr) {
+                    Expr::BinOp(b) => match (b.lhs().as_ref(), b.rhs().as_ref()) {
+                        (Expr::Lit(_), Expr::Lit(_)) => Expr::lit(Interpreter.visit_binop(&b)),
+                        _ => Expr::binop(b),
+                    },
+                    expr => expr,
+                }
+            }
+        }
+
+        /// Counts the nodes under and including `node` without a visitor.
+        fn count(node: NodeRef) -> usize {
+            1 + node.children().map(count).sum::<usize>()
+        }
+
+        #[test]
+        fn mutate_expr() {
+            let one = Box::new(Expr::lit(1));
+            let one_p_one = BinOp::new(Op::Plus, one.clone(), one.clone());
+            let mut ast = Ast::expr(BinOp::new(Op::Plus, Box::new(Expr::binop(one_p_one)), one));
+
+            Negate.visit_ast_mut(&mut ast);
+
+            assert_eq!(Interpreter.visit_ast(&ast), -1);
+        }
+
+        #[test]
+        fn const_fold() {
+            let one = Box::new(Expr::lit(1));
+            let one_p_one = BinOp::new(Op::Plus, one.clone(), one.clone());
+            let ast = Ast::expr(BinOp::new(Op::Times, Box::new(Expr::binop(one_p_one)), one));
+
+            match ConstFold.fold_ast(ast) {
+                Ast::Expr(Expr::Lit(lit)) => assert_eq!(*lit.inner(), 2),
+                folded => panic!("expected a literal, got {:?}", folded),
+            }
+        }
+
+        #[test]
+        fn default_traversal() {
+            let one = Box::new(Expr::lit(1));
+            let one_p_one = BinOp::new(Op::Plus, one.clone(), one.clone());
+            let program = Program::new(vec![
+                Stmt::expr(one_p_one.clone()),
+                Stmt::print(Some(Expr::lit(2))),
+                Stmt::print(None),
+            ]);
+
+            assert_eq!(LitCounter.visit_program(&program), 3);
+
+            let mut counter = OpCounter(0);
+            counter.visit_expr(&Expr::binop(BinOp::new(
+                Op::Minus,
+                Box::new(Expr::binop(one_p_one)),
+                one,
+            )));
+
+            assert_eq!(counter.0, 2);
+        }
+
+        #[test]
+        fn exhaustive_printer() {
+            let one = Box::new(Expr::lit(1));
+            let one_p_one = BinOp::new(Op::Plus, one.clone(), one.clone());
+            let program = Program::new(vec![
+                Stmt::expr(BinOp::new(Op::Times, Box::new(Expr::binop(one_p_one)), one)),
+                Stmt::print(None),
+            ]);
+
+            assert_eq!(Printer.visit_program(&program), "((1 + 1) * 1)\nprint;");
+        }
+
+        #[test]
+        fn try_visitor_stops_early() {
+            let div_zero = BinOp::new(Op::Divide, Box::new(Expr::lit(1)), Box::new(Expr::lit(0)));
+            let program = Program::new(vec![Stmt::expr(div_zero), Stmt::print(Some(Expr::lit(5)))]);
+
+            let mut interpreter = CheckedInterpreter { lits: 0 };
+            let result = interpreter.visit_program(&program);
+
+            assert_eq!(result, Err("division by zero".to_string()));
+            assert_eq!(interpreter.lits, 2);
+        }
+
+        #[test]
+        fn hooks() {
+            let div = BinOp::new(Op::Divide, Box::new(Expr::lit(1)), Box::new(Expr::lit(2)));
+            let plus = BinOp::new(Op::Plus, Box::new(Expr::lit(3)), Box::new(Expr::lit(4)));
+            let program = Program::new(vec![Stmt::expr(div), Stmt::expr(plus), Stmt::print(None)]);
+
+            let mut depth = Depth { depth: 0, max: 0 };
+            depth.visit_program(&program);
+
+            assert_eq!((depth.depth, depth.max), (0, 6));
+
+            let mut first = FirstLit {
+                entered: 0,
+                found: None,
+                stopped: false,
+            };
+            first.visit_program(&program);
+
+            assert_eq!((first.entered, first.found), (1, Some(3)));
+
+            // 1 + 2 stops at the 1, after entering the binop, its operator, its
+            // first operand and the literal, and leaves what it entered
+            let one_p_two = BinOp::new(Op::Plus, Box::new(Expr::lit(1)), Box::new(Expr::lit(2)));
+            let mut trace = Trace::stopping();
+            trace.visit_binop(&one_p_two);
+
+            assert_eq!(trace.events.len(), 8);
+            assert!(trace.balanced());
+
+            // Stopped, the visitor enters nothing else until it is reset
+            trace.events.clear();
+            trace.visit_binop(&one_p_two);
+            assert!(trace.events.is_empty());
+
+            trace.stopped = false;
+            trace.visit_binop(&one_p_two);
+            assert_eq!(trace.events.len(), 8);
+
+            // A stop unwinds through an override walking each statement
+            let mut lits = StopAtLit::default();
+            lits.visit_program(&program);
+            assert_eq!(lits.lits, vec![1]);
+        }
+
+        #[test]
+        fn ctx_visitor() {
+            let one_p_two = BinOp::new(Op::Plus, Box::new(Expr::lit(1)), Box::new(Expr::lit(2)));
+            let program = Program::new(vec![
+                Stmt::expr(BinOp::new(
+                    Op::Times,
+                    Box::new(Expr::binop(one_p_two)),
+                    Box::new(Expr::lit(3)),
+                )),
+                Stmt::print(Some(Expr::lit(4))),
+            ]);
+
+            let mut depths = LitDepths(Vec::new());
+            let mut depth = 0;
+            depths.visit_program(&program, &mut depth);
+
+            assert_eq!(depths.0, vec![(1, 2), (2, 2), (3, 1), (4, 0)]);
+            assert_eq!(depth, 0);
+        }
+
+        #[test]
+        fn typed_visitor() {
+            let one_p_two = BinOp::new(Op::Plus, Box::new(Expr::lit(1)), Box::new(Expr::lit(2)));
+            let program = Program::new(vec![
+                Stmt::expr(Expr::lit(5)),
+                Stmt::print(Some(Expr::binop(one_p_two.clone()))),
+            ]);
+
+            let mut evaluator = Evaluator {
+                printed: Vec::new(),
+            };
+
+            assert_eq!(evaluator.visit_expr(&Expr::binop(one_p_two)), 3);
+
+            evaluator.visit_program(&program);
+            assert_eq!(evaluator.printed, vec![3]);
+        }
+
+        #[test]
+        fn children() {
+            let one_p_two = BinOp::new(Op::Plus, Box::new(Expr::lit(1)), Box::new(Expr::lit(2)));
+            let program = Program::new(vec![
+                Stmt::expr(one_p_two.clone()),
+                Stmt::print(Some(Expr::lit(3))),
+                Stmt::print(None),
+            ]);
+
+            let kinds: Vec<bool> = one_p_two
+                .children()
+                .map(|child| matches!(child, NodeRef::Op(_)))
+                .collect();
+            assert_eq!(kinds, vec![true, false, false]);
+
+            // Program, 3 Stmts, 2 Exprs, BinOp, Op, 2 Exprs, 3 Lits
+            assert_eq!(count(NodeRef::from(&program)), 13);
+        }
+
+        #[test]
+        fn tree_iterators() {
+            let one_p_two = BinOp::new(Op::Plus, Box::new(Expr::lit(1)), Box::new(Expr::lit(2)));
+            let ast = Ast::expr(one_p_two);
+
+            let lit = |(node, depth): (NodeRef, usize)| match node {
+                NodeRef::Lit(lit) => Some((*lit.inner(), depth)),
+                _ => None,
+            };
+            let kind = |(node, _): (NodeRef, usize)| match node {
+                NodeRef::Ast(_) => "ast",
+                NodeRef::Expr(_) => "expr",
+                NodeRef::BinOp(_) => "binop",
+                NodeRef::Op(_) => "op",
+                NodeRef::Lit(_) => "lit",
+                _ => "other",
+            };
+
+            let preorder: Vec<&str> = ast.iter_preorder().map(kind).collect();
+            assert_eq!(
+                preorder,
+                vec!["ast", "expr", "binop", "op", "expr", "lit", "expr", "lit"]
+            );
+
+            let postorder: Vec<&str> = ast.iter_postorder().map(kind).collect();
+            assert_eq!(
+                postorder,
+                vec!["op", "lit", "expr", "lit", "expr", "binop", "expr", "ast"]
+            );
+
+            let bfs: Vec<&str> = ast.iter_bfs().map(kind).collect();
+            assert_eq!(
+                bfs,
+                vec!["ast", "expr", "binop", "op", "expr", "expr", "lit", "lit"]
+            );
+
+            let lits: Vec<(isize, usize)> = ast.iter_preorder().filter_map(lit).collect();
+            assert_eq!(lits, vec![(1, 4), (2, 4)]);
+            assert_eq!(
+                ast.iter_bfs().find(|(_, depth)| *depth == 3).map(kind),
+                Some("op")
+            );
+        }
+
+        #[test]
+        fn descendant_queries() {
+            let one_p_two = BinOp::new(Op::Plus, Box::new(Expr::lit(1)), Box::new(Expr::lit(2)));
+            let program = Program::new(vec![
+                Stmt::expr(one_p_two.clone()),
+                Stmt::print(Some(Expr::lit(3))),
+                Stmt::print(None),
+            ]);
+
+            let lits: Vec<isize> = program
+                .find_all::<Lit>()
+                .into_iter()
+                .map(|lit| *lit.inner())
+                .collect();
+            assert_eq!(lits, vec![1, 2, 3]);
+
+            assert_eq!(program.descendants::<Stmt>().count(), 3);
+            assert!(matches!(
+                program.first::<BinOp>().map(|binop| binop.op()),
+                Some(Op::Plus)
+            ));
+            assert_eq!(one_p_two.first::<Lit>().map(|lit| *lit.inner()), Some(1));
+
+            // A node is not its own descendant
+            assert!(one_p_two.first::<BinOp>().is_none());
+            assert!(program.first::<Program>().is_none());
+        }
+
+        #[test]
+        fn iterative_walk() {
+            let div = BinOp::new(Op::Divide, Box::new(Expr::lit(1)), Box::new(Expr::lit(2)));
+            let plus = BinOp::new(Op::Plus, Box::new(Expr::lit(3)), Box::new(Expr::lit(4)));
+            let program = Program::new(vec![Stmt::expr(div), Stmt::expr(plus), Stmt::print(None)]);
+
+            let mut recursive = Trace::default();
+            recursive.visit_program(&program);
+            let mut iterative = Trace::default();
+            walk_iterative(&mut iterative, NodeRef::from(&program));
+
+            assert_eq!(recursive.events, iterative.events);
+
+            // Both unwind the same way after a Stop
+            let mut recursive = Trace::stopping();
+            recursive.visit_program(&program);
+            let mut iterative = Trace::stopping();
+            walk_iterative(&mut iterative, NodeRef::from(&program));
+
+            assert_eq!(recursive.events, iterative.events);
+            assert!(iterative.balanced());
+
+            let mut depth = Depth { depth: 0, max: 0 };
+            walk_iterative(&mut depth, NodeRef::from(&program));
+
+            assert_eq!((depth.depth, depth.max), (0, 6));
+
+            let mut first = FirstLit {
+                entered: 0,
+                found: None,
+                stopped: false,
+            };
+            walk_iterative(&mut first, NodeRef::from(&program));
+
+            assert_eq!((first.entered, first.found), (1, Some(3)));
+
+            // ((0 - 1) - 1) - ... far deeper than the recursive walk could go
+            let mut expr = Expr::lit(0);
+            for _ in 0..100_000 {
+                expr = Expr::binop(BinOp::new(
+                    Op::Minus,
+                    Box::new(expr),
+                    Box::new(Expr::lit(1)),
+                ));
+            }
+
+            let mut depth = Depth { depth: 0, max: 0 };
+            walk_iterative(&mut depth, NodeRef::from(&expr));
+
+            assert_eq!((depth.depth, depth.max), (0, 200_002));
+
+            // Dropping the chain recurses as deeply as visiting it would
+            std::mem::forget(expr);
+        }
+    }
+
+    mod stack_safe {
+        use crate::ast;
+
+        ast!(
+            #![stack_safe_drop]
+            Lit |isize|,
+            Expr: enum Expr {
+                Neg(Box<Expr>),
+                BinOp: struct BinOp {
+                    lhs: Box<Expr>,
+                    rhs: Box<Expr>,
+                },
+                |Lit|,
+                Error,
+            },
+            Stmt: enum Stmt {
+                |Expr|,
+                Block |Vec<Stmt>|,
+            }
+        );
+
+        use ast::*;
+
+        pub struct Double;
+
+        impl Fold for Double {
+            fn fold_lit(&mut self, lit: Lit) -> Lit {
+                Lit::new(lit.into_inner() * 2)
+            }
+        }
+
+        #[test]
+        fn deep_drop() {
+            let mut expr = Expr::lit(0);
+            for _ in 0..1_000_000 {
+                expr = Expr::neg(Box::new(expr));
+            }
+            drop(expr);
+
+            let mut expr = Expr::lit(0);
+            for i in 0..100_000 {
+                expr = Expr::binop(BinOp::new(Box::new(Expr::lit(i)), Box::new(expr)));
+            }
+            drop(expr);
+
+            let mut stmt = Stmt::expr(Expr::lit(0));
+            for _ in 0..100_000 {
+                stmt = Stmt::block(vec![stmt, Stmt::expr(Expr::lit(1))]);
+            }
+            drop(stmt);
+        }
+
+        #[test]
+        fn fold_after_drop() {
+            let expr = Expr::binop(BinOp::new(
+                Box::new(Expr::neg(Box::new(Expr::lit(1)))),
+                Box::new(Expr::lit(2)),
+            ));
+
+            let doubled = Double.fold_expr(expr);
+            let lits: Vec<isize> = doubled
+                .find_all::<Lit>()
+                .into_iter()
+                .map(|lit| *lit.inner())
+                .collect();
+
+            assert_eq!(lits, vec![2, 4]);
+        }
+    }
+
+    mod arena {
+        use crate::ast;
+
+        ast!(
+            #![arena]
+            Lit |isize|,
+            Expr: enum Expr {
+                BinOp: struct BinOp {
+                    op: enum Op {
+                        Plus,
+                        Times,
+                    },
+                    lhs: Box<Expr>,
+                    rhs: Box<Expr>,
+                },
+                Neg(Box<Expr>),
+                Paren: Paren |Box<Expr>|,
+                |Lit|,
+            },
+            Stmt: enum Stmt {
+                |Expr|,
+                Print(Option<Box<Expr>>),
+            }
+        );
+
+        use ast::*;
+
+        pub struct Interpreter<'ast> {
+            arena: &'ast AstArena,
+        }
+
+        impl<'ast> Visitor<'ast> for Interpreter<'ast> {
+            type Output = isize;
+
+            fn arena(&self) -> &'ast AstArena {
+                self.arena
+            }
+
+            fn combine(&mut self, a: isize, b: isize) -> isize {
+                a + b
+            }
+
+            fn visit_binop(&mut self, b: &'ast BinOp) -> isize {
+                let lhs = self.visit_expr(self.arena.get(*b.lhs()));
+                let rhs = self.visit_expr(self.arena.get(*b.rhs()));
+
+                match b.op() {
+                    Op::Plus => lhs + rhs,
+                    Op::Times => lhs * rhs,
+                }
+            }
+
+            fn visit_expr_neg(&mut self, e: &'ast ExprId) -> isize {
+                -self.visit_expr(self.arena.get(*e))
+            }
+
+            fn visit_lit(&mut self, lit: &'ast Lit) -> isize {
+                *lit.inner()
+            }
+        }
+
+        /// Counts literals through the default traversal.
+        pub struct LitCounter<'ast>(&'ast AstArena);
+
+        impl<'ast> Visitor<'ast> for LitCounter<'ast> {
+            type Output = usize;
+
+            fn arena(&self) -> &'ast AstArena {
+                self.0
+            }
+
+            fn combine(&mut self, a: usize, b: usize) -> usize {
+                a + b
+            }
+
+            fn visit_lit(&mut self, _lit: &'ast Lit) -> usize {
+                1
+            }
+        }
+
+        #[test]
+        fn arena_ids() {
+            let mut arena = AstArena::new();
+
+            // -(2 + 3) * (2 + 3), sharing the sum
+            let two = arena.alloc_expr(Expr::lit(2));
+            let three = arena.alloc_expr(Expr::lit(3));
+            let sum = arena.alloc_expr(Expr::binop(BinOp::new(Op::Plus, two, three)));
+            let neg = arena.alloc_expr(Expr::neg(sum));
+            let product = Expr::binop(BinOp::new(Op::Times, neg, sum));
+            let stmts = [
+                Stmt::expr(product),
+                Stmt::print(Some(sum)),
+                Stmt::print(None),
+            ];
+
+            let mut interpreter = Interpreter { arena: &arena };
+            assert_eq!(interpreter.visit_stmt(&stmts[0]), -25);
+
+            let lits: Vec<usize> = stmts
+                .iter()
+                .map(|stmt| LitCounter(&arena).visit_stmt(stmt))
+                .collect();
+            assert_eq!(lits, vec![4, 2, 0]);
+
+            match &stmts[1] {
+                Stmt::Print(Some(id)) => assert_eq!(*id, sum),
+                stmt => panic!("expected a print, got {:?}", stmt),
+            }
+
+            *arena.get_mut(two) = Expr::lit(7);
+            let mut interpreter = Interpreter { arena: &arena };
+            assert_eq!(interpreter.visit_stmt(&stmts[0]), -100);
+        }
+
+        /// Counts and increments literals through the default traversal.
+        pub struct Increment<'a>(&'a mut AstArena);
+
+        impl VisitorMut for Increment<'_> {
+            type Output = usize;
+
+            fn arena_mut(&mut self) -> &mut AstArena {
+                self.0
+            }
+
+            fn combine(&mut self, a: usize, b: usize) -> usize {
+                a + b
+            }
+
+            fn visit_lit_mut(&mut self, lit: &mut Lit) -> usize {
+                *lit = Lit::new(lit.inner() + 1);
+                1
+            }
+        }
+
+        /// Evaluates with a method for every kind of node.
+        pub struct Exhaustive<'ast>(&'ast AstArena);
+
+        impl<'ast> ExhaustiveVisitor<'ast> for Exhaustive<'ast> {
+            type Output = isize;
+
+            fn arena(&self) -> &'ast AstArena {
+                self.0
+            }
+
+            fn visit_binop(&mut self, b: &'ast BinOp) -> isize {
+                let lhs = self.visit_expr(self.0.get(*b.lhs()));
+                let rhs = self.visit_expr(self.0.get(*b.rhs()));
+
+                match b.op() {
+                    Op::Plus => lhs + rhs,
+                    Op::Times => lhs * rhs,
+                }
+            }
+
+            fn visit_op_plus(&mut self) -> isize {
+                0
+            }
+
+            fn visit_op_times(&mut self) -> isize {
+                0
+            }
+
+            fn visit_expr_neg(&mut self, e: &'ast ExprId) -> isize {
+                -self.visit_expr(self.0.get(*e))
+            }
+
+            fn visit_lit(&mut self, lit: &'ast Lit) -> isize {
+                *lit.inner()
+            }
+
+            fn visit_stmt_print(&mut self, _e: &'ast Option<ExprId>) -> isize {
+                0
+            }
+        }
+
+        pub struct Double<'a>(&'a mut AstArena);
+
+        impl Fold for Double<'_> {
+            fn arena_mut(&mut self) -> &mut AstArena {
+                self.0
+            }
+
+            fn fold_lit(&mut self, lit: Lit) -> Lit {
+                Lit::new(lit.inner() * 2)
+            }
+        }
+
+        #[test]
+        fn ids_followed() {
+            let mut arena = AstArena::new();
+
+            // -((1 + 2))
+            let one = arena.alloc_expr(Expr::lit(1));
+            let two = arena.alloc_expr(Expr::lit(2));
+            let sum = arena.alloc_expr(Expr::binop(BinOp::new(Op::Plus, one, two)));
+            let paren = arena.alloc_expr(Expr::paren(sum));
+            let mut neg = Expr::neg(paren);
+
+            assert_eq!(neg.iter_preorder(&arena).count(), 10);
+            assert_eq!(neg.iter_bfs(&arena).count(), 10);
+            assert_eq!(neg.find_all::<Lit>(&arena).len(), 2);
+            assert_eq!(Exhaustive(&arena).visit_expr(&neg), -3);
+
+            assert_eq!(Increment(&mut arena).visit_expr_mut(&mut neg), 2);
+            assert_eq!(Interpreter { arena: &arena }.visit_expr(&neg), -5);
+
+            let neg = Double(&mut arena).fold_expr(neg);
+            assert_eq!(Interpreter { arena: &arena }.visit_expr(&neg), -10);
+        }
+    }
+
+    mod spans {
+        use crate::ast;
+
+        ast!(
+            #![span]
+            #![stack_safe_drop]
+            Lit |isize|,
+            Expr: enum Expr {
+                BinOp: struct BinOp {
+                    lhs: Box<Expr>,
+                    rhs: Box<Expr>,
+                },
+                |Lit|,
+                Hole: struct Hole {},
+            },
+            Stmt: enum Stmt {
+                |Expr|,
+                Empty,
+            }
+        );
+
+        use ast::*;
+
+        pub struct Double;
+
+        impl Fold for Double {
+            fn fold_lit(&mut self, lit: Lit) -> Lit {
+                Lit::new(lit.inner() * 2, *lit.span())
+            }
+        }
+
+        #[test]
+        fn node_spans() {
+            let one = Expr::lit(Lit::new(1, Span::new(0, 1)));
+            let two = Expr::lit(Lit::new(2, Span::new(4, 5)));
+            let sum = BinOp::new(Box::new(one), Box::new(two), Span::new(0, 5));
+
+            assert_eq!(sum.lhs().span(), Some(&Span::new(0, 1)));
+
+            let expr = Double.fold_expr(Expr::binop(sum));
+            assert_eq!(expr.span(), Some(&Span::new(0, 5)));
+
+            let lits: Vec<(isize, Span)> = expr
+                .find_all::<Lit>()
+                .into_iter()
+                .map(|lit| (*lit.inner(), *lit.span()))
+                .collect();
+            assert_eq!(lits, vec![(2, Span::new(0, 1)), (4, Span::new(4, 5))]);
+
+            assert_eq!(Stmt::expr(expr).span(), Some(&Span::new(0, 5)));
+            assert_eq!(Stmt::empty().span(), None);
+
+            let lit = Lit::from(3);
+            assert_eq!(lit.span(), &Span::default());
+            assert_eq!(lit.with_span(Span::new(7, 8)).span(), &Span::new(7, 8));
+        }
+    }
+
+    mod configured {
+        use crate::ast;
+
+        ast!(
+            #![module = surface, root = Program, visitor = SurfaceVisitor]
+            Lit |isize|,
+            Expr: enum Expr {
+                Neg(Box<Expr>),
+                |Lit|,
+            }
+        );
+
+        ast!(
+            #![module = ir, root = _, visitor = IrVisitor]
+            Imm: Imm |isize|,
+            Value: enum Value {
+                |Imm|,
+                Unit,
+            }
+        );
+
+        use ir::IrVisitor;
+        use surface::SurfaceVisitor;
+
+        #[derive(Default)]
+        pub struct Counter(usize);
+
+        impl<'ast> SurfaceVisitor<'ast> for Counter {
+            type Output = ();
+
+            fn visit_lit(&mut self, _: &'ast surface::Lit) {
+                self.0 += 1;
+            }
+        }
+
+        impl<'ast> IrVisitor<'ast> for Counter {
+            type Output = ();
+
+            fn visit_imm(&mut self, _: &'ast ir::Imm) {
+                self.0 += 1;
+            }
+        }
+
+        #[test]
+        fn two_asts() {
+            let neg = surface::Expr::neg(Box::new(surface::Expr::lit(1)));
+            let program = surface::Program::expr(neg);
+            let value = ir::Value::imm(2);
+
+            let mut counter = Counter::default();
+            counter.visit_program(&program);
+            counter.visit_value(&value);
+            assert_eq!(counter.0, 2);
+
+            assert_eq!(program.iter_preorder().count(), 4);
+            assert_eq!(value.iter_preorder().count(), 2);
+        }
+    }
+
+    mod generics {
+        use crate::ast;
+
+        ast!(
+            #![stack_safe_drop]
+            Name: Name<'src> |&'src str|,
+            Expr: enum Expr<'src, A> where A: Copy {
+                Call: struct Call<'src, A> where A: Copy {
+                    callee: Name,
+                    args: Vec<Expr>,
+                    annotation: A,
+                },
+                Neg(Box<Expr<'src, A>>),
+                |Name|,
+                Lit(isize),
+                Error,
+            }
+        );
+
+        use ast::*;
+
+        #[derive(Default)]
+        pub struct Names<'src>(Vec<&'src str>);
+
+        impl<'ast, 'src: 'ast, A: Copy + 'ast> Visitor<'ast, 'src, A> for Names<'src> {
+            type Output = ();
+
+            fn visit_name(&mut self, name: &'ast Name<'src>) {
+                self.0.push(name.inner());
+            }
+        }
+
+        pub struct Annotate;
+
+        impl<'src> Fold<'src, u8> for Annotate {
+            fn fold_call(&mut self, call: Call<'src, u8>) -> Call<'src, u8> {
+                let call = fold_call(self, call);
+
+                Call::new(
+                    call.callee().clone(),
+                    call.args().clone(),
+                    call.annotation() + 1,
+                )
+            }
+        }
+
+        #[test]
+        fn borrowed_and_generic() {
+            let source = String::from("print len xs");
+            let words: Vec<&str> = source.split(' ').collect();
+
+            let inner = Call::new(Name::from(words[1]), vec![Expr::name(words[2])], 0u8);
+            let outer = Call::new(Name::from(words[0]), vec![Expr::call(inner)], 0);
+            let expr = Expr::neg(Box::new(Expr::call(outer)));
+
+            let mut names = Names::default();
+            names.visit_ast(&Ast::expr(expr.clone()));
+            assert_eq!(names.0, vec!["print", "len", "xs"]);
+
+            let expr = Annotate.fold_expr(expr);
+            let annotations: Vec<u8> = expr
+                .find_all::<Call<_>>()
+                .into_iter()
+                .map(|call| *call.annotation())
+                .collect();
+            assert_eq!(annotations, vec![1, 1]);
+
+            let mut names = Names::default();
+            walk_iterative(&mut names, NodeRef::from(&expr));
+            assert!(names.0.is_empty());
+            assert_eq!(expr.iter_preorder().count(), 9);
+        }
+    }
+
+    mod node_named {
+        use crate::ast;
+
+        ast!(
+            Tree: enum Tree {
+                Node: struct Node { kids: Vec<Tree> },
+                Leaf(isize),
+            }
+        );
+
+        use ast::*;
+
+        #[test]
+        fn type_named_node() {
+            let leaves = vec![Tree::Leaf(1), Tree::Leaf(2)];
+            let tree = Tree::node(Node::new(vec![Tree::node(Node::new(leaves))]));
+
+            assert_eq!(tree.find_all::<Node>().len(), 2);
+            assert_eq!(tree.iter_preorder().count(), 6);
+        }
+    }
+
+    mod attributes {
+        use crate::ast;
+
+        ast!(
+            #![span]
+            #![stack_safe_drop]
+            /// An integer literal.
+            Lit: #[derive(PartialEq, Eq, Hash)] Lit |isize|,
+            Expr: #[derive(PartialEq, Eq, Hash)] enum Expr {
+                /// `lhs op rhs`
+                BinOp: #[derive(PartialEq, Eq, Hash)] struct BinOp {
+                    op: #[derive(PartialEq, Eq, Hash)] enum Op {
+                        Plus,
+                        #[cfg(any())]
+                        Pow,
+                    },
+                    lhs: Box<Expr>,
+                    rhs: Box<Expr>,
+                    #[cfg(any())]
+                    parenthesized: bool,
+                },
+                |Lit|,
+                #[cfg(any())]
+                Neg(Box<Expr>),
+                Error,
+            }
+        );
+
+        use ast::*;
+        use std::collections::HashSet;
+
+        pub struct Identity;
+
+        impl Fold for Identity {}
+
+        fn sum(left: isize, right: isize, span: Span) -> Expr {
+            let lit = |v| Box::new(Expr::lit(Lit::new(v, span)));
+
+            Expr::binop(BinOp::new(Op::plus(), lit(left), lit(right), span))
+        }
+
+        #[test]
+        fn passed_through() {
+            let a = sum(1, 2, Span::new(0, 5));
+            let b = sum(1, 2, Span::new(10, 15));
+            let c = sum(1, 3, Span::new(0, 5));
+
+            assert_eq!(a, b);
+            assert_ne!(a, c);
+
+            let set: HashSet<Expr> = vec![a.clone(), b, c].into_iter().collect();
+            assert_eq!(set.len(), 2);
+
+            assert_eq!(Identity.fold_expr(a.clone()), a);
+        }
+    }
+
+    mod annotated {
+        use crate::ast;
+
+        ast!(
+            #![annotated]
+            Lit |isize|,
+            Expr: enum Expr {
+                BinOp: struct BinOp {
+                    op: enum Op {
+                        Add,
+                        Eq,
+                    },
+                    lhs: Box<Expr>,
+                    rhs: Box<Expr>,
+                },
+                Cond(Box<Cond>),
+                |Lit|,
+                Error,
+            },
+            Cond: struct Cond {
+                cond: Expr,
+                then: Expr,
+                otherwise: Expr,
+            },
+        );
+
+        use ast::*;
+
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        pub enum Ty {
+            Int,
+            Bool,
+        }
+
+        pub struct TypeCheck;
+
+        impl MapAnnotations<(), Ty> for TypeCheck {
+            fn annotate(&mut self, _: ()) -> Ty {
+                Ty::Int
+            }
+
+            fn map_binop(&mut self, binop: BinOp<()>) -> BinOp<Ty> {
+                let ty = match binop.op() {
+                    Op::Add(_) => Ty::Int,
+                    Op::Eq(_) => Ty::Bool,
+                };
+
+                map_binop(self, binop).with_annotation(ty)
+            }
+        }
+
+        fn binop(op: Op<()>, lhs: Expr<()>, rhs: Expr<()>) -> Expr<()> {
+            Expr::binop(BinOp::new(op, Box::new(lhs), Box::new(rhs), ()))
+        }
+
+        /// `if 1 + 2 == 3 then 4 else <error>`, as parsed.
+        fn parsed() -> Expr<()> {
+            let sum = binop(Op::add(()), Expr::lit(1), Expr::lit(2));
+            let cond = binop(Op::eq(()), sum, Expr::lit(3));
+
+            Expr::cond(
+                Box::new(Cond::new(cond, Expr::lit(4), Expr::error(()), ())),
+                (),
+            )
+        }
+
+        #[test]
+        fn trees_that_grow() {
+            let typed: Expr<Ty> = TypeCheck.map_expr(parsed());
+            let ops: Vec<Ty> = typed
+                .find_all::<BinOp<Ty>>()
+                .iter()
+                .map(|binop| *binop.annotation())
+                .collect();
+            assert_eq!(ops, vec![Ty::Bool, Ty::Int]);
+
+            // An enum has the annotation of the node it holds, or its own
+            let cond = typed.first::<Cond<Ty>>().unwrap();
+            assert_eq!(cond.cond().annotation(), &Ty::Bool);
+            assert_eq!(typed.annotation(), &Ty::Int);
+            assert_eq!(
+                typed.clone().with_annotation(Ty::Bool).annotation(),
+                &Ty::Bool
+            );
+
+            let mut next = 0;
+            let numbered: Expr<usize> = parsed().map_annotations(|()| {
+                next += 1;
+                next
+            });
+            let lits: Vec<(isize, usize)> = numbered
+                .find_all::<Lit<usize>>()
+                .iter()
+                .map(|lit| (*lit.inner(), *lit.annotation()))
+                .collect();
+            assert_eq!(lits, vec![(1, 3), (2, 4), (3, 6), (4, 8)]);
+
+            let cond = numbered.first::<Cond<usize>>().unwrap();
+            assert_eq!(cond.annotation(), &10);
+            assert_eq!(cond.otherwise().annotation(), &9);
+            assert_eq!(numbered.annotation(), &11);
+        }
+    }
+
+    mod variant_fields {
+        use crate::ast;
+
+        ast!(
+            #![stack_safe_drop]
+            Name |String|,
+            Expr: enum Expr {
+                Call(Name, Vec<Expr>),
+                Let {
+                    name: Name,
+                    value: Box<Expr>,
+                    body: Box<Expr>,
+                },
+                Lit(isize),
+                Error,
+            }
+        );
+
+        use ast::*;
+
+        #[derive(Default)]
+        pub struct Names(Vec<String>);
+
+        impl<'ast> Visitor<'ast> for Names {
+            type Output = ();
+
+            fn visit_name(&mut self, name: &'ast Name) {
+                self.0.push(name.inner().clone());
+            }
+        }
+
+        pub struct Shout;
+
+        impl Fold for Shout {
+            fn fold_name(&mut self, name: Name) -> Name {
+                Name::new(name.into_inner().to_uppercase())
+            }
+        }
+
+        /// `let x = f(1, g()) in x`
+        fn program() -> Expr {
+            let g = Expr::from((Name::new("g".into()), Vec::new()));
+            let f = Expr::call("f".to_string(), vec![Expr::Lit(1), g]);
+
+            Expr::r#let("x".to_string(), Box::new(f), Box::new(Expr::Lit(0)))
+        }
+
+        #[test]
+        fn every_field() {
+            let mut names = Names::default();
+            names.visit_expr(&program());
+            assert_eq!(names.0, vec!["x", "f", "g"]);
+
+            let mut names = Names::default();
+            names.visit_expr(&Shout.fold_expr(program()));
+            assert_eq!(names.0, vec!["X", "F", "G"]);
+
+            assert_eq!(program().iter_preorder().count(), 8);
+
+            let mut expr = Expr::Lit(0);
+            for i in 0..100_000 {
+                expr = Expr::r#let(i.to_string(), Box::new(Expr::Lit(i)), Box::new(expr));
+            }
+            drop(expr);
+        }
+    }
+
+    mod attribute {
+        #[crate::ast_module]
+        pub mod calc {
+            #![visitor = CalcVisitor]
+
+            use std::collections::HashMap;
+
+            pub enum Expr {
+                BinOp(BinOp),
+                Var(Name),
+                Let(Name, Box<Expr>, Box<Expr>),
+                Lit(isize),
+            }
+
+            pub struct BinOp {
+                pub op: Op,
+                pub lhs: Box<Expr>,
+                pub rhs: Box<Expr>,
+            }
+
+            #[derive(Debug, Clone, PartialEq)]
+            pub enum Op {
+                Plus,
+                Times,
+            }
+
+            pub struct Name(pub String);
+
+            /// The values of the variables in scope.
+            #[asterix(skip)]
+            #[derive(Default)]
+            pub struct Env {
+                pub vars: HashMap<String, isize>,
+            }
+
+            impl Expr {
+                pub fn named(name: &str) -> Self {
+                    Expr::Var(Name::new(name.to_string()))
+                }
+            }
+        }
+
+        use calc::*;
+
+        pub struct Eval(Env);
+
+        impl<'ast> CalcVisitor<'ast> for Eval {
+            type Output = isize;
+
+            fn visit_expr_let(
+                &mut self,
+                name: &'ast Name,
+                value: &'ast Box<Expr>,
+                body: &'ast Box<Expr>,
+            ) -> isize {
+                let value = self.visit_expr(value);
+                let shadowed = self.0.vars.insert(name.0.clone(), value);
+                let body = self.visit_expr(body);
+                match shadowed {
+                    Some(shadowed) => self.0.vars.insert(name.0.clone(), shadowed),
+                    None => self.0.vars.remove(&name.0),
+                };
+                body
+            }
+
+            fn visit_name(&mut self, name: &'ast Name) -> isize {
+                self.0.vars[&name.0]
+            }
+
+            fn visit_binop(&mut self, b: &'ast BinOp) -> isize {
+                let lhs = self.visit_expr(&b.lhs);
+                let rhs = self.visit_expr(&b.rhs);
+
+                match b.op {
+                    Op::Plus => lhs + rhs,
+                    Op::Times => lhs * rhs,
+                }
+            }
+
+            fn visit_expr_lit(&mut self, lit: &'ast isize) -> isize {
+                *lit
+            }
+        }
+
+        #[test]
+        fn hand_written_module() {
+            // `let x = 3 in x * (x + 1)`
+            let sum = BinOp::new(Op::Plus, Box::new(Expr::named("x")), Box::new(Expr::Lit(1)));
+            let product = BinOp::new(Op::Times, Box::new(Expr::named("x")), Box::new(sum.into()));
+            let program = Expr::r#let(
+                Name::from("x".to_string()),
+                Box::new(Expr::Lit(3)),
+                Box::new(product.into()),
+            );
+
+            assert_eq!(Eval(Env::default()).visit_expr(&program), 12);
+            assert_eq!(Op::Plus, Op::Plus.clone());
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    mod serde_tagged {
+        use crate::ast;
+
+        ast!(
+            #![serde(tag = "type", content = "value", rename_all = "snake_case")]
+            #![span]
+            Lit |isize|,
+            Expr: enum Expr {
+                BinOp: struct BinOp {
+                    op: enum Op {
+                        Plus,
+                        Minus,
+                    },
+                    left_hand: Box<Expr>,
+                    right_hand: Box<Expr>,
+                },
+                |Lit|,
+            }
+        );
+
+        use ast::*;
+
+        #[test]
+        fn adjacently_tagged() {
+            let expr = Expr::binop(BinOp::new(
+                Op::Minus,
+                Box::new(Expr::lit(Lit::new(2, Span::new(0, 1)))),
+                Box::new(Expr::lit(1)),
+                Span::new(0, 5),
+            ));
+
+            let json = serde_json::to_string(&Ast::expr(expr)).unwrap();
+            assert_eq!(
+                json,
+                concat!(
+                    r#"{"type":"expr","value":{"type":"bin_op","value":{"op":{"type":"minus"},"#,
+                    r#""left_hand":{"type":"lit","value":[2,{"start":0,"end":1}]},"#,
+                    r#""right_hand":{"type":"lit","value":[1,{"start":0,"end":0}]},"#,
+                    r#""span":{"start":0,"end":5}}}}"#
+                )
+            );
+
+            let ast: Ast = serde_json::from_str(&json).unwrap();
+            assert_eq!(serde_json::to_string(&ast).unwrap(), json);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    mod serde_external {
+        use crate::ast;
+
+        ast!(
+            #![serde]
+            Lit |isize|,
+            Stmt: enum Stmt {
+                Print(Option<Lit>),
+                Exit,
+            }
+        );
+
+        use ast::*;
+
+        #[test]
+        fn externally_tagged() {
+            let stmts = vec![
+                Stmt::print(Some(Lit::new(3))),
+                Stmt::print(None),
+                Stmt::exit(),
+            ];
+
+            let json = serde_json::to_string(&stmts).unwrap();
+            assert_eq!(json, r#"[{"Print":3},{"Print":null},"Exit"]"#);
+
+            let parsed: Vec<Stmt> = serde_json::from_str(&json).unwrap();
+            assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    mod serde_internal {
+        use crate::ast;
+
+        ast!(
+            #![root = _]
+            #![serde(tag = "kind")]
+            Expr: enum Expr {
+                Lit: struct Lit {
+                    value: isize,
+                },
+                Neg: struct Neg {
+                    expr: Box<Expr>,
+                },
+                Call { args: Vec<Expr> },
+                Hole,
+            }
+        );
+
+        use ast::*;
+
+        #[test]
+        fn internally_tagged() {
+            let lit = || Expr::lit(Lit::new(1));
+            let expr = Expr::call(vec![Expr::neg(Neg::new(Box::new(lit()))), Expr::hole()]);
+
+            let json = serde_json::to_string(&expr).unwrap();
+            assert_eq!(
+                json,
+                concat!(
+                    r#"{"kind":"Call","args":[{"kind":"Neg","expr":{"kind":"Lit","value":1}},"#,
+                    r#"{"kind":"Hole"}]}"#
+                )
+            );
+
+            let parsed: Expr = serde_json::from_str(&json).unwrap();
+            assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+        }
+    }
 }